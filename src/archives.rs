@@ -0,0 +1,67 @@
+//! Papertrail's archive index.
+//!
+//! `GET /api/v1/archives.json` lists every hourly archive the account has, each with a link to
+//! download it. The link is normally the same `/archives/<hour>/download` endpoint, which
+//! redirects to storage, but it may instead point at storage directly with a presigned URL.
+//!
+//! See <https://www.papertrail.com/help/permanent-log-archives/>.
+
+use anyhow::{Context, Result};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+const API_URL: &str = "https://papertrailapp.com/api/v1";
+
+#[derive(Debug, Deserialize)]
+struct IndexEntry {
+    /// E.g. `2024-01-02-05.tsv.gz`.
+    filename: String,
+    #[serde(rename = "_links")]
+    links: IndexLinks,
+}
+
+#[derive(Debug, Deserialize)]
+struct IndexLinks {
+    download: IndexLink,
+}
+
+#[derive(Debug, Deserialize)]
+struct IndexLink {
+    href: String,
+}
+
+/// Fetch the index, returning each archive's download URL keyed by its hour (`YYYY-MM-DD-HH`).
+pub async fn fetch(client: &Client) -> Result<HashMap<String, Url>> {
+    let entries: Vec<IndexEntry> = client
+        .get(format!("{}/archives.json", API_URL))
+        .send()
+        .await?
+        .error_for_status()
+        .context("Couldn't fetch the archive index")?
+        .json()
+        .await
+        .context("Invalid response for the archive index")?;
+    entries
+        .into_iter()
+        .map(|entry| {
+            let url = Url::parse(&entry.links.download.href).with_context(|| {
+                format!(
+                    "Invalid download link for {}: {}",
+                    entry.filename, entry.links.download.href
+                )
+            })?;
+            let hour = entry
+                .filename
+                .strip_suffix(".tsv.gz")
+                .unwrap_or(&entry.filename)
+                .to_string();
+            Ok((hour, url))
+        })
+        .collect()
+}
+
+/// Whether `url` is on the API itself, and so needs the API token, rather than presigned storage.
+pub fn is_api_url(url: &Url) -> bool {
+    Url::parse(API_URL).is_ok_and(|api| api.origin() == url.origin())
+}
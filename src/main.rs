@@ -1,20 +1,68 @@
+mod archives;
+mod search;
+
 use anyhow::{Context, Result};
+use async_compression::tokio::bufread::GzipDecoder as GzipReadDecoder;
 use async_compression::tokio::write::GzipDecoder;
-use chrono::{Datelike, DurationRound, Local, NaiveDateTime, TimeDelta, Timelike};
-use clap::Parser;
-use csv_async::{AsyncReaderBuilder, AsyncWriterBuilder};
+use bytes::Bytes;
+use chrono::{DateTime, Datelike, DurationRound, Local, NaiveDateTime, TimeDelta, Timelike, Utc};
+use clap::{ArgGroup, Parser, ValueEnum};
+use csv_async::{AsyncReaderBuilder, AsyncSerializer, AsyncWriter, AsyncWriterBuilder};
 use dotenv::dotenv;
+use futures::FutureExt;
 use lazy_static::lazy_static;
 use reqwest::StatusCode;
-use reqwest::{header::HeaderMap, Client};
-use std::net::Ipv4Addr;
-use std::path::PathBuf;
+use reqwest::{
+    header::{HeaderMap, HeaderName, LAST_MODIFIED},
+    Client, Url,
+};
+use sha2::{Digest as _, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::task::{Context as TaskContext, Poll};
 use std::time::Duration;
 use thiserror::Error;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::AsyncSeekExt;
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio_stream::StreamExt;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeek, AsyncWrite, AsyncWriteExt, BufReader,
+    BufWriter,
+};
+use tokio::process::Command;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::Instant;
+use tokio_stream::wrappers::LinesStream;
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+/// File descriptors kept free for stdio and the runtime itself.
+const RESERVED_FILE_DESCRIPTORS: usize = 32;
+
+/// Extensions of the files a download can produce.
+const ARCHIVE_EXTENSIONS: [&str; 3] = ["tsv.gz", "tsv", "csv"];
+
+/// Capacity of the in-process pipe between a gzip decoder and the reader parsing its output.
+const DECODE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Upper bound on the backoff between retries.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Free space in --out needed to resume downloads paused by --throttle-on-disk-full.
+const DISK_SPACE_TO_RESUME: u64 = 16 * 1024 * 1024;
+
+/// How often to check for free space while downloads are paused.
+const DISK_SPACE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Exit status of an otherwise successful run that saw an event at or above --alert-on-severity.
+const ALERT_EXIT_CODE: i32 = 3;
+
 lazy_static! {
     static ref DEFAULT_CONCURRENCY: String =
         std::thread::available_parallelism().map_or_else(|_| String::from("4"), |n| n.to_string());
@@ -27,67 +75,970 @@ struct Event {
     pub received_at: String,
     pub source_id: u32,
     pub source_name: String,
-    pub source_ip: Ipv4Addr,
+    pub source_ip: SourceIp,
     pub facility_name: String,
     pub severity_name: String,
     pub program: String,
     pub message: String,
 }
 
+/// A source address as it appears in an archive. Anything that isn't a valid IPv4 or IPv6
+/// address is kept verbatim so that --validate-ip decides what to do with it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum SourceIp {
+    Addr(IpAddr),
+    Raw(String),
+}
+
+impl std::fmt::Display for SourceIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceIp::Addr(addr) => addr.fmt(f),
+            SourceIp::Raw(raw) => f.write_str(raw),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for SourceIp {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        Ok(match raw.parse() {
+            Ok(addr) => SourceIp::Addr(addr),
+            Err(_) => SourceIp::Raw(raw),
+        })
+    }
+}
+
+impl serde::Serialize for SourceIp {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Maps numeric source ids to human-readable labels.
+type SourceMap = HashMap<u32, String>;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(group(ArgGroup::new("conversion").args(["csv", "convert_local", "saved_search"]).multiple(true)))]
 struct Cli {
     /// Which archive files to download, in the format "YYYY-MM-DD-HH". Will be ignored if --start and --end are supplied.
     files: Vec<String>,
     /// API key for Papertrail.
-    #[arg(id = "api-token", value_name = "API_TOKEN", env = "PAPERTRAIL_API_TOKEN", long, value_parser = api_client_from_token)]
-    api_client: Client,
+    #[arg(
+        id = "api-token",
+        value_name = "API_TOKEN",
+        env = "PAPERTRAIL_API_TOKEN",
+        long
+    )]
+    api_token: Option<String>,
+    /// Read the API token from the OS keyring instead of --api-token.
+    #[arg(long)]
+    use_keyring: bool,
+    /// Store the --api-token value in the OS keyring and exit.
+    #[arg(long, requires = "api-token")]
+    store_in_keyring: bool,
+    /// Keyring service the API token is stored under.
+    #[arg(long, value_name = "NAME", default_value = env!("CARGO_PKG_NAME"))]
+    keyring_service: String,
+    /// Keyring account the API token is stored under.
+    #[arg(long, value_name = "NAME", default_value = "api-token")]
+    keyring_account: String,
     /// How many files to download at once.
     #[arg(short, long, default_value = &**DEFAULT_CONCURRENCY)]
     concurrency: usize,
     /// Where to download the files.
     #[arg(short, long, default_value = ".")]
     out: PathBuf,
-    /// How long in milliseconds to wait in between requests.
+    /// How long in milliseconds to wait in between requests. Not applied when every file fits within --concurrency.
     #[arg(short, long, default_value = "200")]
     throttle_duration: u64,
-    /// Decode from gzip before writing.
+    /// Decode from gzip before writing. With --csv, the decoded .tsv is kept alongside the CSV.
     #[arg(short, long)]
     deflate: bool,
-    /// Convert the downloaded files to CSV.
-    #[arg(long, requires = "deflate")]
+    /// Convert the downloaded files to CSV. Archives are decoded straight into the conversion and not kept; see --tee-raw.
+    #[arg(long)]
     csv: bool,
     /// Start of datetime window
-    #[arg(long, requires = "start")]
+    #[arg(long, requires = "end")]
     start: Option<NaiveDateTime>,
     /// End of datetime window
-    #[arg(long, requires = "end")]
+    #[arg(long, requires = "start")]
     end: Option<NaiveDateTime>,
+    /// CSV file of "id,label" lines used to replace the source name of matching events during conversion.
+    #[arg(long, value_name = "FILE", requires = "conversion", value_parser = source_map_from_file)]
+    source_map: Option<SourceMap>,
+    /// Flush converted output to its .partial file (or with --rotate-every, the archive's staging file) every N events, so progress on a large file can be watched, at the cost of throughput. Output is still only kept once the whole file converts; by default it is only flushed then.
+    #[arg(long, value_name = "N", requires = "conversion", value_parser = clap::value_parser!(u64).range(1..))]
+    checkpoint_interval: Option<u64>,
+    /// Convert already-downloaded archives (a file, or a directory searched recursively) to CSV instead of downloading. Output mirrors the input layout under --out. Files ending in .ndjson or .jsonl are read as one JSON event per line.
+    #[arg(long, value_name = "PATH", conflicts_with = "files")]
+    convert_local: Option<PathBuf>,
+    /// When an archive row fails to parse, report its record number, the field and value at fault and the raw line before aborting.
+    #[arg(long, requires = "conversion")]
+    strict_conversion: bool,
+    /// With --convert-local, only check that each archive parses, reporting its event count or the first error, and write nothing.
+    #[arg(long, requires = "convert_local")]
+    dry_run_convert: bool,
+    /// Cancel the remaining downloads once this many files have failed.
+    #[arg(long, value_name = "N")]
+    abort_after: Option<NonZeroUsize>,
+    /// Print the same download error at most once per DURATION (e.g. 30s, 5m), counting the repeats instead. Errors that differ only in their hour count as the same.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    min_interval_between_identical_errors: Option<TimeDelta>,
+    /// How many times to retry a download whose connection dropped partway through the body.
+    #[arg(long, default_value = "3")]
+    retries: u32,
+    /// When a download fails because the disk is full, pause all downloads until --out has space again, then retry it. Once a download has waited DURATION (e.g. 10m, 1h), it fails, and so does any later download that runs out of space.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    throttle_on_disk_full: Option<TimeDelta>,
+    /// Base delay in milliseconds for retries. Backoff doubles with each further attempt, up to a minute.
+    #[arg(long, default_value = "1000")]
+    retry_delay: u64,
+    /// How to randomize retry backoff, so that many clients retrying at once spread out.
+    #[arg(long, value_enum, default_value_t = JitterStrategy::Full)]
+    retry_jitter_strategy: JitterStrategy,
+    /// Upper bound on open file descriptors. Output is serialized once the budget is used up. By default the soft limit is raised as far as --concurrency needs, where the OS allows it.
+    #[arg(long, value_name = "N")]
+    max_open_files: Option<usize>,
+    /// Also write every downloaded event into one JSON document at PATH, keyed by archive hour: {"2024-01-02-05": [...], ...}. Each hour is held in memory until it has been converted.
+    #[arg(long, value_name = "PATH", requires = "deflate")]
+    group_by_hour: Option<PathBuf>,
+    /// Flag downloaded archives smaller than this many (compressed) bytes, which usually means the server-side archive is truncated.
+    #[arg(long, value_name = "N")]
+    min_bytes: Option<u64>,
+    /// What to do when an archive is smaller than --min-bytes.
+    #[arg(long, value_enum, default_value_t = Reaction::Warn, requires = "min_bytes")]
+    on_small_archive: Reaction,
+    /// Resolve HOST to ADDR instead of using DNS, like curl's --resolve. Can be given multiple times. The port is only validated; requests still go to the port in the URL.
+    #[arg(long, value_name = "HOST:PORT:ADDR", value_parser = parse_resolve_override)]
+    resolve: Vec<ResolveOverride>,
+    /// Don't download anything; report which hours of the --start/--end window are present, empty or missing in --out.
+    #[arg(long, requires = "start")]
+    summary_only: bool,
+    /// After downloading, fail unless every hour of the --start/--end window, counted independently of the download list, has output in --out or was reported by --partial-window as not archived yet.
+    #[arg(long, requires = "start", conflicts_with_all = ["count_only", "rotate_every"])]
+    verify_window_coverage: bool,
+    /// Print reports as JSON instead of human-readable text.
+    #[arg(long)]
+    json: bool,
+    /// Pipe each decoded archive through this shell command and save its output instead. A non-zero exit status fails the file.
+    #[arg(long, value_name = "CMD", requires = "deflate")]
+    filter_command: Option<String>,
+    /// Report downloads and write --group-by-hour output strictly in hour order. Hours that finish early are held in memory, JSON included, until every earlier hour is done.
+    #[arg(long)]
+    ordered_output: bool,
+    /// Limit conversion to this many events per second across all files, trading throughput for lower CPU use on shared hosts.
+    #[arg(long, value_name = "N", requires = "conversion", value_parser = clap::value_parser!(u64).range(1..))]
+    max_events_per_sec: Option<u64>,
+    /// How to handle source IPs that are neither IPv4 nor IPv6 addresses during conversion.
+    #[arg(long, value_enum, default_value_t = IpValidation::Strict)]
+    validate_ip: IpValidation,
+    /// Download and decode archives without saving anything, printing how many lines each hour and the whole run hold.
+    #[arg(long, conflicts_with_all = ["deflate", "group_by_hour", "csv", "zip", "manifest"])]
+    count_only: bool,
+    /// With --count-only, count parsed events rather than lines. Slower, but catches malformed rows.
+    #[arg(long, requires = "count_only")]
+    count_events: bool,
+    /// What to do when there turns out to be nothing to download, e.g. a window that covers no hours.
+    #[arg(long, value_enum, default_value_t = Reaction::Warn)]
+    on_empty_window: Reaction,
+    /// Run the Papertrail saved search with this id over the --start/--end window and write the matching events to search-<ID>.csv in --out.
+    #[arg(long, value_name = "ID", requires = "start", conflicts_with_all = ["files", "convert_local"])]
+    saved_search: Option<u64>,
+    /// With --csv, only convert archives whose hour began less than DURATION ago (e.g. 90m, 36h, 7d); older archives are only saved as downloaded.
+    #[arg(long, value_name = "DURATION", requires = "csv", value_parser = parse_duration)]
+    convert_newer_than: Option<TimeDelta>,
+    /// Exit with status 3 once the run finishes if any converted event was at LEVEL or more severe (e.g. error also matches critical, alert and emergency). Failures still exit with status 1.
+    #[arg(long, value_name = "LEVEL", value_enum, requires = "conversion")]
+    alert_on_severity: Option<Severity>,
+    /// Collapse every run of whitespace in converted messages, including tabs and newlines, to a single space.
+    #[arg(long, requires = "conversion")]
+    canonicalize_messages: bool,
+    /// With --csv, write converted events to numbered files in --out (events-00000.csv, ...) of N events each, counted across the whole run, instead of one CSV per hour. Each hour's events are written together once its archive has been fully downloaded, in the order the downloads finish, so failed hours add nothing.
+    #[arg(long, value_name = "N", requires = "csv", conflicts_with_all = ["convert_local", "saved_search"], value_parser = clap::value_parser!(u64).range(1..))]
+    rotate_every: Option<u64>,
+    /// Tally converted events by severity and print a bar chart for each archive and for the whole run. With --json, print the tallies as JSON at the end instead.
+    #[arg(long, requires = "conversion")]
+    severity_histogram: bool,
+    /// Write each hour's output to a YYYY-MM-DD/ subdirectory of --out, named by the hour alone (e.g. 2024-01-02/05.tsv.gz).
+    #[arg(long)]
+    organize_by_day: bool,
+    /// With --csv, decode archives whose compressed size is at most BYTES entirely into memory before converting them, rather than streaming them into the conversion.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        requires = "csv",
+        conflicts_with = "deflate"
+    )]
+    decompress_to_memory: Option<u64>,
+    /// With --csv, also keep each decoded archive as .tsv, written in the same pass that feeds the conversion. --deflate does the same.
+    #[arg(long, requires = "csv", conflicts_with = "decompress_to_memory")]
+    tee_raw: bool,
+    /// Header used to send each download request's generated id, which is also included in any error for that request.
+    #[arg(long, value_name = "NAME", default_value = "X-Request-Id", value_parser = HeaderName::from_str)]
+    request_id_header: HeaderName,
+    /// Also collect every downloaded file into one zip at PATH, each entry named as it is under --out. Entries are added as downloads complete.
+    #[arg(long, value_name = "PATH")]
+    zip: Option<PathBuf>,
+    /// How to compress --zip entries.
+    #[arg(long, value_enum, default_value_t = ZipCompression::Deflate, requires = "zip")]
+    zip_compression: ZipCompression,
+    /// Write a JSON manifest of every file downloaded, with its size and a SHA-256 of its final contents computed while it was written.
+    #[arg(long, value_name = "PATH")]
+    manifest: Option<PathBuf>,
+    /// Download whichever hours of the window are archived, listing the trailing hours that aren't yet (404s after the newest archived hour) at the end instead of counting them as failures.
+    #[arg(long)]
+    partial_window: bool,
+    /// Print the fields of converted events with their types, then exit. Use --json for JSON, or --sql-schema for a CREATE TABLE statement.
+    #[arg(long)]
+    dump_event_schema: bool,
+    /// With --dump-event-schema, print the schema as SQL.
+    #[arg(long, requires = "dump_event_schema", conflicts_with = "json")]
+    sql_schema: bool,
+    /// Refuse to start if the window or file list comes to more than N archives, unless --yes is also given.
+    #[arg(long, value_name = "N")]
+    max_archives: Option<usize>,
+    /// Go ahead with a run that exceeds --max-archives.
+    #[arg(long, requires = "max_archives")]
+    yes: bool,
+    /// Before starting, delete `.partial` files left in --out by interrupted runs. Only this tool's own partial outputs are removed.
+    #[arg(long)]
+    purge_partial_on_start: bool,
+    /// Only speak HTTP/1.1 to the API, for proxies that break HTTP/2. By default the version is negotiated, falling back to HTTP/1.1 for the rest of the run if a request fails before getting a response.
+    #[arg(long)]
+    force_http1: bool,
+    /// Fetch the archive index once and download each hour from the link it lists, instead of asking the download endpoint for every hour. Links that point outside the API (presigned storage URLs) are requested without the API token.
+    #[arg(long, conflicts_with_all = ["convert_local", "saved_search"])]
+    from_index: bool,
+    /// Skip an hour if everything it would write already exists with a modification time at or after the server's Last-Modified for it. Hours whose response has no Last-Modified are downloaded as usual.
+    #[arg(long, conflicts_with = "count_only")]
+    no_clobber_newer: bool,
+}
+
+/// Whether an hour of the window has been downloaded, as reported by --summary-only.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Coverage {
+    Present,
+    Empty,
+    Missing,
+}
+
+impl Coverage {
+    fn name(self) -> &'static str {
+        match self {
+            Coverage::Present => "present",
+            Coverage::Empty => "empty",
+            Coverage::Missing => "missing",
+        }
+    }
+
+    fn symbol(self) -> char {
+        match self {
+            Coverage::Present => '#',
+            Coverage::Empty => '0',
+            Coverage::Missing => '.',
+        }
+    }
+}
+
+/// A DNS override given with --resolve.
+#[derive(Clone, Debug)]
+struct ResolveOverride {
+    host: String,
+    addr: SocketAddr,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum IpValidation {
+    /// Fail the conversion.
+    Strict,
+    /// Warn and keep the raw value.
+    Lenient,
+}
+
+/// How to react to a suspicious but recoverable condition.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Reaction {
+    /// Fail with an error.
+    Error,
+    /// Print a warning and carry on.
+    Warn,
+    /// Carry on silently.
+    Ok,
+}
+
+/// Jitter strategies for retry backoff, as described in
+/// <https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/>.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum JitterStrategy {
+    /// Sleep a random time between zero and the exponential backoff.
+    Full,
+    /// Sleep half the exponential backoff plus a random time up to the other half.
+    Equal,
+    /// Sleep a random time between the base delay and three times the previous sleep.
+    Decorrelated,
+}
+
+/// How --zip compresses its entries.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ZipCompression {
+    /// Store entries as they are. Best for .tsv.gz archives, which are already compressed.
+    Stored,
+    /// Deflate each entry.
+    Deflate,
+}
+
+/// Syslog severities, most severe first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Severity {
+    Emergency,
+    Alert,
+    Critical,
+    Error,
+    Warning,
+    Notice,
+    Info,
+    Debug,
+}
+
+impl Severity {
+    /// Parse a `severity_name` as it appears in archives.
+    fn from_name(name: &str) -> Option<Self> {
+        Self::from_str(name, true).ok()
+    }
+}
+
+/// Paces events shared by all conversions, for --max-events-per-sec.
+#[derive(Debug)]
+struct RateLimiter {
+    per_second: u64,
+    /// Events are let through in batches so each wait is long enough for the timer to be accurate.
+    batch: u64,
+    /// Events paced so far, across every file, so small files don't each start a batch of their own.
+    events: AtomicU64,
+    next_free: std::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(per_second: u64) -> Self {
+        Self {
+            per_second,
+            batch: (per_second / 100).max(1),
+            events: AtomicU64::new(0),
+            next_free: std::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until the next event may be processed.
+    async fn pace(&self) {
+        if !self
+            .events
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.batch)
+        {
+            return;
+        }
+        let start = {
+            let mut next_free = self.next_free.lock().expect("rate limiter lock poisoned");
+            let start = (*next_free).max(Instant::now());
+            *next_free =
+                start + Duration::from_secs_f64(self.batch as f64 / self.per_second as f64);
+            start
+        };
+        tokio::time::sleep_until(start).await;
+    }
+}
+
+/// Holds back download errors already printed within a window, for --min-interval-between-identical-errors.
+#[derive(Debug)]
+struct ErrorSuppressor {
+    window: Duration,
+    /// When each error was last printed, and how many times it has come up since.
+    seen: HashMap<String, (Instant, u64)>,
+}
+
+impl ErrorSuppressor {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Print `error` for the hour `time`, unless the same error was printed less than a window ago.
+    fn report(&mut self, time: &str, error: &anyhow::Error) {
+        let key = error.root_cause().to_string().replace(time, "<hour>");
+        let now = Instant::now();
+        match self.seen.get_mut(&key) {
+            Some((printed, repeats)) if now.duration_since(*printed) < self.window => {
+                *repeats += 1;
+                return;
+            }
+            Some((printed, repeats)) => {
+                print_repeats(&key, *repeats);
+                (*printed, *repeats) = (now, 0);
+            }
+            None => {
+                self.seen.insert(key, (now, 0));
+            }
+        }
+        eprintln!("Error: {:?}", error);
+    }
+
+    /// Report the repeats still held back.
+    fn finish(self) {
+        for (key, (_, repeats)) in self.seen {
+            print_repeats(&key, repeats);
+        }
+    }
+}
+
+fn print_repeats(key: &str, repeats: u64) {
+    if repeats > 0 {
+        eprintln!("(repeated {} times) {}", repeats, key);
+    }
+}
+
+/// A successfully downloaded archive.
+struct Downloaded {
+    time: String,
+    /// The hour's events as a JSON array, for --group-by-hour.
+    grouped: Option<Vec<u8>>,
+    /// How many lines (or events, with --count-events) the archive holds, for --count-only.
+    count: Option<u64>,
+    outputs: Vec<OutputFile>,
+    severities: Option<Histogram>,
+    /// Set when --no-clobber-newer kept the local copy.
+    skipped: bool,
+}
+
+/// What [`Run::write_archive`] produced.
+struct Written {
+    grouped: Option<Vec<u8>>,
+    /// None if the archive was only held in memory.
+    archive: Option<Digest>,
+    converted: Option<Converted>,
+}
+
+/// What [`Run::write_events`] produced.
+struct Converted {
+    /// None if the events went to the --rotate-every output rather than a file of their own.
+    digest: Option<Digest>,
+    severities: Option<Histogram>,
+    /// With --rotate-every, the converted rows, waiting for [`RotatingCsv::append`] once the archive is complete.
+    staged: Option<File>,
+}
+
+/// Events per severity, for --severity-histogram.
+type Histogram = BTreeMap<String, u64>;
+
+/// Per-archive and aggregate severity tallies for --severity-histogram.
+#[derive(Default)]
+struct SeverityReport {
+    archives: BTreeMap<String, Histogram>,
+}
+
+impl SeverityReport {
+    /// Record an archive's tally, printing it straight away unless the report is JSON.
+    fn add(&mut self, name: String, histogram: Histogram, json: bool) {
+        if !json {
+            println!("{}", name);
+            print_histogram(&histogram);
+        }
+        self.archives.insert(name, histogram);
+    }
+
+    fn finish(self, json: bool) -> Result<()> {
+        let mut total = Histogram::new();
+        for histogram in self.archives.values() {
+            for (severity, count) in histogram {
+                *total.entry(severity.clone()).or_default() += count;
+            }
+        }
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&serde_json::json!({
+                    "archives": self.archives,
+                    "total": total,
+                }))?
+            );
+        } else {
+            println!("Total");
+            print_histogram(&total);
+        }
+        Ok(())
+    }
+}
+
+/// A file written for a download, as recorded in the --manifest.
+#[derive(Debug, serde::Serialize)]
+struct OutputFile {
+    /// The hour downloaded, or for a --rotate-every file the first hour it holds rows from.
+    time: String,
+    /// Relative to --out.
+    path: PathBuf,
+    bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+/// Size and, if requested, SHA-256 of bytes passed through a [`HashingWriter`].
+#[derive(Debug)]
+struct Digest {
+    bytes: u64,
+    sha256: Option<String>,
+}
+
+/// Passes writes through to `inner`, counting and optionally hashing them on the way so outputs don't need to be re-read.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Option<Sha256>,
+    bytes: u64,
+}
+
+impl<W> HashingWriter<W> {
+    fn new(inner: W, hash: bool) -> Self {
+        Self {
+            inner,
+            hasher: hash.then(Sha256::new),
+            bytes: 0,
+        }
+    }
+
+    fn digest(&self) -> Digest {
+        Digest {
+            bytes: self.bytes,
+            sha256: self
+                .hasher
+                .as_ref()
+                .map(|hasher| format!("{:x}", hasher.clone().finalize())),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            if let Some(hasher) = &mut this.hasher {
+                hasher.update(&buf[..written]);
+            }
+            this.bytes += written as u64;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+type BoxedWriter<'a> = Pin<Box<dyn AsyncWrite + Send + 'a>>;
+
+/// Writes everything to `primary` and copies whatever it accepted on to `secondary`, so one pass can feed two consumers.
+/// At most one write's worth of bytes is held back while `secondary` catches up.
+struct TeeWriter<A, B> {
+    primary: A,
+    secondary: B,
+    pending: Vec<u8>,
+}
+
+impl<A, B: AsyncWrite + Unpin> TeeWriter<A, B> {
+    fn new(primary: A, secondary: B) -> Self {
+        Self {
+            primary,
+            secondary,
+            pending: vec![],
+        }
+    }
+
+    /// Pass held-back bytes on to `secondary`.
+    fn poll_pending(&mut self, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        while !self.pending.is_empty() {
+            match Pin::new(&mut self.secondary).poll_write(cx, &self.pending) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()))
+                }
+                Poll::Ready(Ok(written)) => {
+                    self.pending.drain(..written);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<A: AsyncWrite + Unpin, B: AsyncWrite + Unpin> AsyncWrite for TeeWriter<A, B> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        std::task::ready!(this.poll_pending(cx))?;
+        let poll = Pin::new(&mut this.primary).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.pending.extend_from_slice(&buf[..written]);
+            // The bytes are accepted either way; anything left over goes out on the next call.
+            if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        std::task::ready!(this.poll_pending(cx))?;
+        std::task::ready!(Pin::new(&mut this.primary).poll_flush(cx))?;
+        Pin::new(&mut this.secondary).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        std::task::ready!(this.poll_pending(cx))?;
+        std::task::ready!(Pin::new(&mut this.primary).poll_shutdown(cx))?;
+        Pin::new(&mut this.secondary).poll_shutdown(cx)
+    }
+}
+
+/// The --filter-command's stdin. Filters like `head -n` or `grep -m` may exit before reading all
+/// their input; once one has, the rest is thrown away so the body is still read to the end and the
+/// command's exit status alone decides the outcome.
+struct FilterInput<W> {
+    inner: W,
+    closed: bool,
+}
+
+impl<W> FilterInput<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            closed: false,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for FilterInput<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.closed {
+            return Poll::Ready(Ok(buf.len()));
+        }
+        match std::task::ready!(Pin::new(&mut self.inner).poll_write(cx, buf)) {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                self.closed = true;
+                Poll::Ready(Ok(buf.len()))
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        if self.closed {
+            return Poll::Ready(Ok(()));
+        }
+        match std::task::ready!(Pin::new(&mut self.inner).poll_flush(cx)) {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                self.closed = true;
+                Poll::Ready(Ok(()))
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.closed {
+            return Poll::Ready(Ok(()));
+        }
+        match std::task::ready!(Pin::new(&mut self.inner).poll_shutdown(cx)) {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Poll::Ready(Ok(())),
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+/// A writer that throws its input away, keeping only a count of the lines in it.
+#[derive(Default)]
+struct LineCounter {
+    lines: u64,
+}
+
+impl AsyncWrite for LineCounter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.lines += buf.iter().filter(|byte| **byte == b'\n').count() as u64;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Streams hour-keyed JSON arrays into a single JSON object.
+struct GroupedJson {
+    out: BufWriter<File>,
+    empty: bool,
+}
+
+impl GroupedJson {
+    async fn create(path: &Path) -> Result<Self> {
+        let mut out = BufWriter::new(
+            File::create(path)
+                .await
+                .with_context(|| format!("Couldn't create {}", path.display()))?,
+        );
+        out.write_all(b"{").await?;
+        Ok(Self { out, empty: true })
+    }
+
+    async fn push(&mut self, time: &str, events: &[u8]) -> Result<()> {
+        if !self.empty {
+            self.out.write_all(b",").await?;
+        }
+        self.empty = false;
+        self.out
+            .write_all(serde_json::to_string(time)?.as_bytes())
+            .await?;
+        self.out.write_all(b":").await?;
+        self.out.write_all(events).await?;
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<()> {
+        self.out.write_all(b"}").await?;
+        self.out.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Where [`Run::write_events`] serializes to.
+enum CsvOutput {
+    File(Box<AsyncSerializer<HashingWriter<File>>>),
+    /// Headerless rows in a temporary file, for --rotate-every.
+    Staged(Box<AsyncSerializer<File>>),
+}
+
+impl CsvOutput {
+    async fn serialize(&mut self, event: Event) -> Result<()> {
+        match self {
+            Self::File(writer) => writer.serialize(event).await?,
+            Self::Staged(writer) => writer.serialize(event).await?,
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        match self {
+            Self::File(writer) => writer.flush().await?,
+            Self::Staged(writer) => writer.flush().await?,
+        }
+        Ok(())
+    }
+}
+
+/// Spreads converted events over numbered CSV files of `every` events each, for --rotate-every.
+struct RotatingCsv {
+    dir: PathBuf,
+    every: u64,
+    /// Whether to hash the files for the --manifest.
+    hash: bool,
+    files: usize,
+    in_file: u64,
+    /// The current file, with its name and the first hour it holds rows from.
+    writer: Option<(AsyncWriter<HashingWriter<File>>, PathBuf, String)>,
+    /// Files that are done, for the --manifest.
+    outputs: Vec<OutputFile>,
+}
+
+impl RotatingCsv {
+    fn new(dir: PathBuf, every: u64, hash: bool) -> Self {
+        Self {
+            dir,
+            every,
+            hash,
+            files: 0,
+            in_file: 0,
+            writer: None,
+            outputs: vec![],
+        }
+    }
+
+    /// Add the rows `time`'s conversion staged, rolling to new files as needed.
+    async fn append(&mut self, time: &str, mut staged: File) -> Result<()> {
+        staged.rewind().await?;
+        let mut rows = AsyncReaderBuilder::new()
+            .has_headers(false)
+            .create_reader(staged);
+        let mut records = rows.byte_records();
+        while let Some(record) = records.next().await {
+            let record = record?;
+            if self.writer.is_none() || self.in_file == self.every {
+                self.roll(time).await?;
+            }
+            let (writer, ..) = self.writer.as_mut().expect("a file was opened above");
+            writer.write_byte_record(&record).await?;
+            self.in_file += 1;
+        }
+        self.flush().await
+    }
+
+    /// Finish the current file and start the next one, which gets its own header row.
+    async fn roll(&mut self, time: &str) -> Result<()> {
+        self.close().await?;
+        let name = PathBuf::from(format!("events-{:05}.csv", self.files));
+        let path = self.dir.join(&name);
+        let file = File::create(&path)
+            .await
+            .map_err(open_error)
+            .with_context(|| format!("Couldn't create {}", path.display()))?;
+        let mut writer =
+            AsyncWriterBuilder::new().create_writer(HashingWriter::new(file, self.hash));
+        writer
+            .write_record(EVENT_FIELDS.iter().map(|field| field.name))
+            .await?;
+        self.writer = Some((writer, name, time.to_string()));
+        self.files += 1;
+        self.in_file = 0;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if let Some((writer, ..)) = &mut self.writer {
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Finish the current file, if any, recording it for the --manifest.
+    async fn close(&mut self) -> Result<()> {
+        if let Some((writer, path, time)) = self.writer.take() {
+            let digest = writer.into_inner().await?.digest();
+            self.outputs.push(OutputFile {
+                time,
+                path,
+                bytes: digest.bytes,
+                sha256: digest.sha256,
+            });
+        }
+        Ok(())
+    }
+
+    /// Finish the last file, returning every file written, for the --manifest.
+    async fn finish(&mut self) -> Result<Vec<OutputFile>> {
+        self.close().await?;
+        Ok(std::mem::take(&mut self.outputs))
+    }
+}
+
+/// Collects downloaded files into a single zip, for --zip.
+struct ZipOutput {
+    writer: async_zip::tokio::write::ZipFileWriter<File>,
+    compression: ZipCompression,
+}
+
+impl ZipOutput {
+    async fn create(path: &Path, compression: ZipCompression) -> Result<Self> {
+        let file = File::create(path)
+            .await
+            .with_context(|| format!("Couldn't create {}", path.display()))?;
+        Ok(Self {
+            writer: async_zip::tokio::write::ZipFileWriter::with_tokio(file),
+            compression,
+        })
+    }
+
+    /// Copy the file at `path` into the zip as `name`.
+    async fn add(&mut self, path: &Path, name: &Path) -> Result<()> {
+        let compression = match self.compression {
+            ZipCompression::Stored => async_zip::Compression::Stored,
+            ZipCompression::Deflate => async_zip::Compression::Deflate,
+        };
+        // Zip entry names always use forward slashes.
+        let name = name
+            .iter()
+            .map(|part| part.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        // Opened before the entry, so a missing file doesn't leave a local header behind.
+        let mut file = File::open(path).await.map_err(open_error)?.compat();
+        let mut entry = self
+            .writer
+            .write_entry_stream(async_zip::ZipEntryBuilder::new(name.into(), compression))
+            .await?;
+        futures::io::copy(&mut file, &mut entry).await?;
+        entry.close().await?;
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<()> {
+        self.writer.close().await?.into_inner().shutdown().await?;
+        Ok(())
+    }
 }
 
 impl Cli {
-    /// If the start and end args are supplied, generate a list of files to download.
-    fn file_names(&self) -> Option<Vec<String>> {
-        let (mut start, end) = (
+    /// The --start/--end window in UTC, if both were supplied.
+    fn window(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        Some((
             self.start?
                 .and_local_timezone(Local)
                 .earliest()
                 .unwrap_or_else(move || {
                     panic!("--start {} falls within a DST gap", &self.start.unwrap())
                 })
-                .to_utc()
-                .duration_trunc(TimeDelta::hours(1))
-                // TODO: Handle this possibility more gracefully as part of general argument validation
-                .with_context(move || format!("Invalid start datetime: {}", &self.start.unwrap()))
-                .unwrap(),
+                .to_utc(),
             self.end?
                 .and_local_timezone(Local)
                 .earliest()
                 .unwrap_or_else(move || {
-                    panic!("--end {} falls within a DST gap", &self.start.unwrap())
+                    panic!("--end {} falls within a DST gap", &self.end.unwrap())
                 })
                 .to_utc(),
-        );
+        ))
+    }
+
+    /// If the start and end args are supplied, generate a list of files to download.
+    fn file_names(&self) -> Option<Vec<String>> {
+        let (start, end) = self.window()?;
+        let mut start = start
+            .duration_trunc(TimeDelta::hours(1))
+            // TODO: Handle this possibility more gracefully as part of general argument validation
+            .with_context(move || format!("Invalid start datetime: {}", &self.start.unwrap()))
+            .unwrap();
 
         let mut names: Vec<String> = vec![];
         while start <= end {
@@ -109,55 +1060,901 @@ impl Cli {
         Some(names)
     }
 
-    async fn download_file(&self, time: String) -> Result<String> {
-        let response = self
-            .api_client
-            .get(format!(
-                "https://papertrailapp.com/api/v1/archives/{}/download",
-                time
-            ))
-            .send()
-            .await?;
+    /// Backoff before the given retry attempt (starting at 1), given the previous delay.
+    fn retry_delay(&self, attempt: u32, previous: Duration) -> Duration {
+        let base = Duration::from_millis(self.retry_delay);
+        let exponential = base
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(MAX_RETRY_DELAY);
+        match self.retry_jitter_strategy {
+            JitterStrategy::Full => random_between(Duration::ZERO, exponential),
+            JitterStrategy::Equal => {
+                exponential / 2 + random_between(Duration::ZERO, exponential / 2)
+            }
+            JitterStrategy::Decorrelated => {
+                random_between(base, previous.saturating_mul(3)).min(MAX_RETRY_DELAY)
+            }
+        }
+    }
 
-        match response.status() {
-            StatusCode::OK => {
-                let mut byte_stream = response.bytes_stream();
-                let ext: &str = if self.deflate { "tsv" } else { "tsv.gz" };
-                // TODO: Use an intermediary temp file here
-                let mut file = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .open(self.out.join(format!("{}.{}", &time, ext)))
-                    .await?;
-                let mut out = BufWriter::new(&mut file);
-                if self.deflate {
-                    let mut decoder = GzipDecoder::new(out);
-                    while let Some(item) = byte_stream.next().await {
-                        tokio::io::copy(&mut item?.as_ref(), &mut decoder).await?;
-                    }
-                    decoder.shutdown().await?;
-                    out = decoder.into_inner();
-                } else {
-                    while let Some(item) = byte_stream.next().await {
-                        tokio::io::copy(&mut item?.as_ref(), &mut out).await?;
-                    }
+    /// Where an hour's output goes under --out, without an extension.
+    fn output_stem(&self, time: &str) -> PathBuf {
+        match time.split_at_checked(10) {
+            Some((date, hour)) if self.organize_by_day => {
+                self.out.join(date).join(hour.trim_start_matches('-'))
+            }
+            _ => self.out.join(time),
+        }
+    }
+
+    /// Where an hour's archive and CSV go, given its [`Cli::output_stem`], for whichever of them are written.
+    fn output_paths(&self, time: &str, stem: &Path) -> (Option<PathBuf>, Option<PathBuf>) {
+        let converted = (self.csv && self.is_recent(time)).then(|| stem.with_extension("csv"));
+        // Converted archives are decoded straight into the CSV, and only kept with --tee-raw or --deflate.
+        let archive = (converted.is_none() || self.tee_raw || self.deflate).then(|| {
+            let decoded = self.deflate || converted.is_some();
+            stem.with_extension(if decoded { "tsv" } else { "tsv.gz" })
+        });
+        (archive, converted)
+    }
+
+    /// Whether an archive hour is within --convert-newer-than, if given. Hours that don't parse count as recent.
+    fn is_recent(&self, time: &str) -> bool {
+        let Some(max_age) = self.convert_newer_than else {
+            return true;
+        };
+        match NaiveDateTime::parse_from_str(&format!("{}:00", time), "%Y-%m-%d-%H:%M") {
+            Ok(hour) => Utc::now() - hour.and_utc() <= max_age,
+            Err(_) => true,
+        }
+    }
+
+    /// Whether every one of `outputs` exists and was modified no earlier than the response's Last-Modified.
+    async fn local_copy_is_newer<'p>(
+        &self,
+        outputs: impl Iterator<Item = &'p PathBuf>,
+        response: &reqwest::Response,
+    ) -> Result<bool> {
+        let Some(last_modified) = response
+            .headers()
+            .get(LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+        else {
+            return Ok(false);
+        };
+        let mut any = false;
+        for path in outputs {
+            let modified = match tokio::fs::metadata(path).await {
+                Ok(metadata) => DateTime::<Utc>::from(metadata.modified()?),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Couldn't check {}", path.display()))
                 }
+            };
+            if modified < last_modified {
+                return Ok(false);
+            }
+            any = true;
+        }
+        Ok(any)
+    }
+
+    /// Decode an archive without saving it, counting its lines or, with --count-events, its parsed events.
+    async fn count_archive(&self, time: &str, response: reqwest::Response) -> Result<u64> {
+        let mut byte_stream = response
+            .bytes_stream()
+            .map(|item| item.map_err(|e| CliError::InterruptedBody(time.to_string(), e)));
+        if !self.count_events {
+            let mut decoder = GzipDecoder::new(LineCounter::default());
+            copy_body(&mut byte_stream, &mut decoder).await?;
+            decoder.shutdown().await?;
+            return Ok(decoder.into_inner().lines);
+        }
+
+        let (writer, reader) = tokio::io::duplex(DECODE_BUFFER_SIZE);
+        let feed = async {
+            let mut decoder = GzipDecoder::new(writer);
+            copy_body(&mut byte_stream, &mut decoder).await?;
+            // Dropping the decoder closes the pipe, ending the reader below.
+            decoder.shutdown().await?;
+            Ok::<_, anyhow::Error>(())
+        };
+        let count = async {
+            let mut records = AsyncReaderBuilder::new()
+                .has_headers(false)
+                .delimiter(b'\t')
+                .create_deserializer(reader)
+                .into_deserialize::<Event>();
+            let mut events: u64 = 0;
+            while let Some(record) = records.next().await {
+                record?;
+                events += 1;
+            }
+            Ok(events)
+        };
+        let ((), events) = tokio::try_join!(feed, count)?;
+        Ok(events)
+    }
+
+    /// Apply --min-bytes to an archive whose download was `downloaded` (compressed) bytes.
+    fn check_size(&self, time: &str, downloaded: u64) -> Result<()> {
+        if let Some(min_bytes) = self.min_bytes.filter(|min_bytes| downloaded < *min_bytes) {
+            let error = CliError::ArchiveTooSmall(time.to_string(), downloaded, min_bytes);
+            match self.on_small_archive {
+                Reaction::Error => return Err(error.into()),
+                Reaction::Warn => eprintln!("Warning: {}", error),
+                Reaction::Ok => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy a response body to `out`, decompressing it and piping it through --filter-command as requested.
+    async fn decode_body<S, W>(
+        &self,
+        time: &str,
+        byte_stream: &mut S,
+        out: &mut W,
+        decompress: bool,
+    ) -> Result<u64>
+    where
+        S: Stream<Item = Result<Bytes, CliError>> + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        if let Some(command) = &self.filter_command {
+            let mut child = shell_command(command)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .with_context(|| format!("Couldn't run filter command: {}", command))?;
+            let stdin = child.stdin.take().expect("stdin is piped");
+            let mut stdout = child.stdout.take().expect("stdout is piped");
+            let feed = async {
+                let mut decoder = GzipDecoder::new(FilterInput::new(stdin));
+                let downloaded = copy_body(byte_stream, &mut decoder).await?;
+                // Dropping the decoder closes the command's stdin so it can finish.
+                decoder.shutdown().await?;
+                Ok::<_, anyhow::Error>(downloaded)
+            };
+            let drain = async {
+                tokio::io::copy(&mut stdout, out).await?;
+                Ok(())
+            };
+            let (downloaded, ()) = tokio::try_join!(feed, drain)?;
+            let status = child.wait().await?;
+            if !status.success() {
+                return Err(CliError::FilterFailed(time.to_string(), status).into());
+            }
+            Ok(downloaded)
+        } else if decompress {
+            let mut decoder = GzipDecoder::new(out);
+            let downloaded = copy_body(byte_stream, &mut decoder).await?;
+            decoder.shutdown().await?;
+            Ok(downloaded)
+        } else {
+            copy_body(byte_stream, out).await
+        }
+    }
+
+    fn keyring_entry(&self) -> Result<keyring::Entry> {
+        keyring::Entry::new(&self.keyring_service, &self.keyring_account)
+            .map_err(|e| CliError::Keyring(e).into())
+    }
+
+    /// The API token, from the OS keyring with --use-keyring or from --api-token otherwise.
+    async fn api_token(&self) -> Result<String> {
+        if !self.use_keyring {
+            return self.api_token.clone().ok_or(CliError::MissingToken.into());
+        }
+        let entry = self.keyring_entry()?;
+        // Keyring backends block, and some start their own runtime.
+        match tokio::task::spawn_blocking(move || entry.get_password()).await? {
+            Ok(token) => Ok(token),
+            Err(keyring::Error::NoEntry) => Err(CliError::MissingKeyringToken(
+                self.keyring_service.clone(),
+                self.keyring_account.clone(),
+            )
+            .into()),
+            Err(e) => Err(CliError::Keyring(e).into()),
+        }
+    }
+
+    /// Save --api-token in the OS keyring for later use with --use-keyring.
+    async fn store_in_keyring(&self) -> Result<()> {
+        let token = self.api_token.clone().ok_or(CliError::MissingToken)?;
+        let entry = self.keyring_entry()?;
+        tokio::task::spawn_blocking(move || entry.set_password(&token))
+            .await?
+            .map_err(CliError::Keyring)?;
+        println!(
+            "Stored API token in the OS keyring (service {}, account {})",
+            self.keyring_service, self.keyring_account
+        );
+        Ok(())
+    }
+
+    /// Build a client for the API, or with no token, one for presigned links.
+    fn build_api_client(&self, token: Option<&str>, http1_only: bool) -> Result<Client> {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = token {
+            headers.insert(
+                "X-Papertrail-Token",
+                reqwest::header::HeaderValue::from_str(token).context("Invalid API token")?,
+            );
+        }
+        let mut builder = Client::builder().default_headers(headers);
+        if http1_only {
+            builder = builder.http1_only();
+        }
+        for entry in &self.resolve {
+            builder = builder.resolve(&entry.host, entry.addr);
+        }
+        builder.build().context("Couldn't build client")
+    }
+
+    /// How many files each download or local conversion keeps open at once.
+    fn files_per_task(&self) -> usize {
+        if self.count_only {
+            0
+        } else if self.convert_local.is_some() {
+            2
+        } else {
+            1 + usize::from(self.csv && (self.tee_raw || self.deflate))
+        }
+    }
+
+    /// Work out how many output files may be open at once, raising the soft descriptor limit if needed.
+    fn open_file_budget(&self) -> Result<usize> {
+        let wanted = self
+            .max_open_files
+            .unwrap_or(self.concurrency * (self.files_per_task() + 1) + RESERVED_FILE_DESCRIPTORS);
+        let limit = raise_open_file_limit(wanted);
+        if limit < wanted && self.max_open_files.is_some() {
+            eprintln!(
+                "Warning: --max-open-files {} exceeds the OS limit, using {}",
+                wanted, limit
+            );
+        }
+        // Every in-flight request also holds a socket.
+        limit
+            .checked_sub(RESERVED_FILE_DESCRIPTORS + self.concurrency)
+            .filter(|budget| *budget >= self.files_per_task())
+            .map(|budget| budget.min(Semaphore::MAX_PERMITS))
+            .ok_or_else(|| CliError::OpenFileLimit(limit, self.concurrency).into())
+    }
+
+    /// Check which hours of the window already have output in the output directory.
+    async fn coverage(&self) -> Result<Vec<(String, Coverage)>> {
+        let mut coverage = vec![];
+        for time in self.file_names().unwrap_or_default() {
+            let status = self.hour_coverage(&time).await?;
+            coverage.push((time, status));
+        }
+        Ok(coverage)
+    }
+
+    /// Check whether one hour already has output in the output directory.
+    async fn hour_coverage(&self, time: &str) -> Result<Coverage> {
+        let mut status = Coverage::Missing;
+        for ext in ARCHIVE_EXTENSIONS {
+            let path = self.output_stem(time).with_extension(ext);
+            if path.try_exists()? {
+                status = if has_content(&path).await? {
+                    Coverage::Present
+                } else {
+                    Coverage::Empty
+                };
+                if status == Coverage::Present {
+                    break;
+                }
+            }
+        }
+        Ok(status)
+    }
 
-                out.shutdown().await?;
+    /// Fail with the hours of the window that have no output, for --verify-window-coverage.
+    ///
+    /// The hours are counted by offset from the start of the window rather than taken from
+    /// [`Cli::file_names`], so a bug there that leaves hours out can't also hide them here.
+    async fn verify_window_coverage(&self, not_archived: &[String]) -> Result<()> {
+        let (start, end) = self
+            .window()
+            .expect("--verify-window-coverage requires --start and --end");
+        let first = start
+            .duration_trunc(TimeDelta::hours(1))
+            .context("Invalid start of window")?;
+        let mut uncovered = vec![];
+        let mut last_covered = None;
+        for offset in 0..=(end - first).num_hours() {
+            let time = (first + TimeDelta::hours(offset))
+                .format("%Y-%m-%d-%H")
+                .to_string();
+            if self.hour_coverage(&time).await? == Coverage::Missing {
+                uncovered.push((offset, time));
+            } else {
+                last_covered = Some(offset);
+            }
+        }
+        // Only hours after the last one with output can be explained as not archived yet; a 404
+        // anywhere before it is a gap.
+        let gaps: Vec<_> = uncovered
+            .into_iter()
+            .filter(|(offset, time)| {
+                !(last_covered.is_some_and(|last| *offset > last) && not_archived.contains(time))
+            })
+            .map(|(_, time)| time)
+            .collect();
+        if gaps.is_empty() {
+            return Ok(());
+        }
+        Err(CliError::CoverageGaps(gaps.len(), gaps.join(", ")).into())
+    }
 
-                if self.csv {
-                    file.rewind().await?;
-                    convert_to_csv(file, self.out.join(format!("{}.csv", &time))).await?
+    fn dump_event_schema(&self) -> Result<()> {
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&EVENT_FIELDS)?);
+        } else if self.sql_schema {
+            let columns = EVENT_FIELDS
+                .iter()
+                .map(|field| format!("    {} {} NOT NULL", field.name, field.sql_type))
+                .collect::<Vec<_>>();
+            println!("CREATE TABLE events (\n{}\n);", columns.join(",\n"));
+        } else {
+            let width = EVENT_FIELDS
+                .iter()
+                .map(|field| field.name.len())
+                .max()
+                .unwrap_or(0);
+            for field in &EVENT_FIELDS {
+                println!("{:width$}  {}", field.name, field.rust_type);
+            }
+            let header = EVENT_FIELDS
+                .iter()
+                .map(|field| field.name)
+                .collect::<Vec<_>>();
+            println!("\nCSV header and JSON keys: {}", header.join(","));
+        }
+        Ok(())
+    }
+
+    async fn summarize_coverage(&self) -> Result<()> {
+        let coverage = self.coverage().await?;
+        if self.json {
+            let mut report: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+            for status in [Coverage::Present, Coverage::Empty, Coverage::Missing] {
+                report.insert(status.name(), vec![]);
+            }
+            for (time, status) in &coverage {
+                report.entry(status.name()).or_default().push(time);
+            }
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(());
+        }
+
+        print!("{:10}", "");
+        for hour in 0..24 {
+            print!(" {:02}", hour);
+        }
+        let mut day = "";
+        for (time, status) in &coverage {
+            let (date, hour) = time.split_at(10);
+            if date != day {
+                day = date;
+                print!("\n{}", date);
+                let first_hour: usize = hour[1..].parse()?;
+                print!("{}", "   ".repeat(first_hour));
+            }
+            print!("  {}", status.symbol());
+        }
+        println!();
+        println!(
+            "{} present ({}), {} empty ({}), {} missing ({})",
+            coverage
+                .iter()
+                .filter(|(_, status)| *status == Coverage::Present)
+                .count(),
+            Coverage::Present.symbol(),
+            coverage
+                .iter()
+                .filter(|(_, status)| *status == Coverage::Empty)
+                .count(),
+            Coverage::Empty.symbol(),
+            coverage
+                .iter()
+                .filter(|(_, status)| *status == Coverage::Missing)
+                .count(),
+            Coverage::Missing.symbol(),
+        );
+        Ok(())
+    }
+}
+
+/// A run of the tool: the parsed [`Cli`] plus the clients, limits and counters it sets up and
+/// updates as it goes.
+struct Run {
+    cli: Cli,
+    api_client: Option<Client>,
+    /// Used instead of `api_client` once a request has needed the HTTP/1.1 fallback.
+    http1_client: Option<Client>,
+    http1_fallback: AtomicBool,
+    /// Download links from the archive index, for --from-index.
+    archive_urls: Option<HashMap<String, Url>>,
+    /// A client without the API token, for presigned links.
+    presigned_client: Option<Client>,
+    rotator: Option<tokio::sync::Mutex<RotatingCsv>>,
+    /// Held while downloads are paused for --throttle-on-disk-full.
+    disk_pause: tokio::sync::Mutex<()>,
+    /// Set once a download gave up waiting for space, so the rest fail without waiting again.
+    disk_full_gave_up: AtomicBool,
+    /// How many times downloads have resumed after waiting for space.
+    disk_resumes: AtomicU64,
+    /// How free space in --out is checked; tests substitute their own.
+    free_space: fn(&Path) -> Option<u64>,
+    /// Set when an event meets --alert-on-severity.
+    alerted: AtomicBool,
+    /// How many messages --canonicalize-messages changed.
+    canonicalized: AtomicU64,
+    event_limiter: Option<RateLimiter>,
+    open_files: Option<Semaphore>,
+}
+
+impl std::ops::Deref for Run {
+    type Target = Cli;
+
+    fn deref(&self) -> &Cli {
+        &self.cli
+    }
+}
+
+impl Run {
+    fn new(cli: Cli) -> Self {
+        Self {
+            cli,
+            api_client: None,
+            http1_client: None,
+            http1_fallback: AtomicBool::new(false),
+            archive_urls: None,
+            presigned_client: None,
+            rotator: None,
+            disk_pause: tokio::sync::Mutex::new(()),
+            disk_full_gave_up: AtomicBool::new(false),
+            disk_resumes: AtomicU64::new(0),
+            free_space,
+            alerted: AtomicBool::new(false),
+            canonicalized: AtomicU64::new(0),
+            event_limiter: None,
+            open_files: None,
+        }
+    }
+
+    async fn download_file(&self, time: String) -> Result<Downloaded> {
+        let time = time.as_str();
+        self.retry_download(time, |request_id| async move {
+            self.try_download_file(time, &request_id).await
+        })
+        .await
+    }
+
+    /// Make download attempts for the hour `time` until one succeeds, retrying dropped connections and
+    /// waiting out a full disk as requested.
+    async fn retry_download<T, F, Fut>(&self, time: &str, try_download: F) -> Result<T>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        let mut delay = Duration::from_millis(self.retry_delay);
+        let disk_full_timeout = self
+            .throttle_on_disk_full
+            .map(|timeout| timeout.to_std().unwrap_or(Duration::MAX));
+        let mut out_of_space_since = None;
+        loop {
+            if disk_full_timeout.is_some() {
+                // Don't start while another download is waiting for space.
+                drop(self.disk_pause.lock().await);
+            }
+            // A fresh id per attempt, so each request can be told apart in the provider's logs.
+            let request_id = uuid::Uuid::new_v4().to_string();
+            match try_download(request_id.clone())
+                .await
+                .with_context(|| format!("Request {} for {} failed", request_id, time))
+            {
+                Err(e) if attempt < self.retries && is_interrupted_body(&e) => {
+                    attempt += 1;
+                    eprintln!("Retrying {} ({}/{}): {:#}", time, attempt, self.retries, e);
+                    delay = self.retry_delay(attempt, delay);
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) if disk_full_timeout.is_some() && is_disk_full(&e) => {
+                    let resumes = self.disk_resumes.load(Ordering::Relaxed);
+                    let since = *out_of_space_since.get_or_insert_with(Instant::now);
+                    let timeout = disk_full_timeout.expect("checked above");
+                    if !self.wait_for_disk_space(since, timeout, resumes).await {
+                        return Err(e.context(CliError::DiskFull(self.out.display().to_string())));
+                    }
                 }
+                result => return result,
+            }
+        }
+    }
+
+    /// Pause downloads until --out has room again, after a download failed for lack of space when
+    /// `resumes` downloads had resumed. Returns false once `timeout` has passed since `since`, or if an
+    /// earlier wait gave up.
+    async fn wait_for_disk_space(&self, since: Instant, timeout: Duration, resumes: u64) -> bool {
+        let _pause = self.disk_pause.lock().await;
+        if self.disk_full_gave_up.load(Ordering::Relaxed) || since.elapsed() >= timeout {
+            self.disk_full_gave_up.store(true, Ordering::Relaxed);
+            return false;
+        }
+        // Space was already waited for since this download failed.
+        if self.disk_resumes.load(Ordering::Relaxed) != resumes {
+            return true;
+        }
+        eprintln!(
+            "Disk full, waiting for space in {} before resuming downloads",
+            self.out.display()
+        );
+        loop {
+            let remaining = timeout.saturating_sub(since.elapsed());
+            if remaining.is_zero() {
+                self.disk_full_gave_up.store(true, Ordering::Relaxed);
+                return false;
+            }
+            // Deleting the failed download's partial output may already have freed enough to pass the
+            // check below, so always wait first; otherwise an archive that can't fit would retry in a
+            // tight loop. Without a way to check, just retry after each wait.
+            tokio::time::sleep(DISK_SPACE_POLL_INTERVAL.min(remaining)).await;
+            if (self.free_space)(&self.out).is_none_or(|free| free >= DISK_SPACE_TO_RESUME) {
+                break;
+            }
+        }
+        self.disk_resumes.fetch_add(1, Ordering::Relaxed);
+        eprintln!("Disk space available again, resuming downloads");
+        true
+    }
+
+    async fn try_download_file(&self, time: &str, request_id: &str) -> Result<Downloaded> {
+        // Hours the index doesn't list may have been archived since it was fetched.
+        let url = match self.archive_urls.as_ref().and_then(|urls| urls.get(time)) {
+            Some(url) => url.clone(),
+            None => Url::parse(&format!(
+                "https://papertrailapp.com/api/v1/archives/{}/download",
+                time
+            ))?,
+        };
+        log::debug!(
+            "Requesting {} as {} {}",
+            time,
+            self.request_id_header,
+            request_id
+        );
+        let response = if archives::is_api_url(&url) {
+            self.send(|client| {
+                client
+                    .get(url.clone())
+                    .header(&self.request_id_header, request_id)
+            })
+            .await?
+        } else {
+            self.presigned_client
+                .as_ref()
+                .expect("presigned client is built with the archive index")
+                .get(url)
+                .header(&self.request_id_header, request_id)
+                .send()
+                .await?
+        };
 
-                Ok(time.to_string())
+        match response.status() {
+            StatusCode::OK if self.count_only => Ok(Downloaded {
+                time: time.to_string(),
+                grouped: None,
+                count: Some(self.count_archive(time, response).await?),
+                outputs: vec![],
+                severities: None,
+                skipped: false,
+            }),
+            StatusCode::OK => {
+                let stem = self.output_stem(time);
+                if let Some(parent) = stem.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                let (archive, converted) = self.output_paths(time, &stem);
+                let in_memory = converted.is_some()
+                    && self.decompress_to_memory.is_some_and(|limit| {
+                        response
+                            .content_length()
+                            .is_some_and(|length| length <= limit)
+                    });
+                let own_csv = converted.as_ref().filter(|_| self.rotator.is_none());
+                if self.no_clobber_newer
+                    && self
+                        .local_copy_is_newer(archive.iter().chain(own_csv), &response)
+                        .await?
+                {
+                    // Dropping the response abandons the body unread.
+                    return Ok(Downloaded {
+                        time: time.to_string(),
+                        grouped: None,
+                        count: None,
+                        outputs: vec![],
+                        severities: None,
+                        skipped: true,
+                    });
+                }
+                let byte_stream = response
+                    .bytes_stream()
+                    .map(|item| item.map_err(|e| CliError::InterruptedBody(time.to_string(), e)));
+                self.save_archive(time, byte_stream, archive, converted, in_memory)
+                    .await
             }
             code => Err(CliError::BadResponse(time.to_string(), code).into()),
         }
     }
 
+    /// Write an archive's body to its outputs, only moving them into place once all of it was written.
+    async fn save_archive<S>(
+        &self,
+        time: &str,
+        byte_stream: S,
+        archive: Option<PathBuf>,
+        converted: Option<PathBuf>,
+        in_memory: bool,
+    ) -> Result<Downloaded>
+    where
+        S: Stream<Item = Result<Bytes, CliError>> + Unpin + Send,
+    {
+        let result = self
+            .write_archive(
+                time,
+                byte_stream,
+                archive.as_deref().map(partial_path).as_deref(),
+                converted.as_deref().map(partial_path).as_deref(),
+                in_memory,
+            )
+            .await;
+        let result = match result {
+            Ok(mut written) => self
+                .append_rotated(time, &mut written)
+                .await
+                .map(|()| written),
+            Err(e) => Err(e),
+        };
+
+        let own_csv = converted.as_ref().filter(|_| self.rotator.is_none());
+        // Only promote the outputs once everything was written, so a truncated file never
+        // takes the place of a complete one.
+        for path in archive.iter().chain(own_csv) {
+            if result.is_ok() {
+                tokio::fs::rename(partial_path(path), path).await?;
+            } else {
+                tokio::fs::remove_file(partial_path(path)).await.ok();
+            }
+        }
+
+        let mut written = result?;
+        let severities = written
+            .converted
+            .as_mut()
+            .and_then(|converted| converted.severities.take());
+        let outputs = archive
+            .zip(written.archive)
+            .into_iter()
+            .chain(converted.zip(written.converted.and_then(|converted| converted.digest)))
+            .map(|(path, digest)| OutputFile {
+                time: time.to_string(),
+                path: path.strip_prefix(&self.out).unwrap_or(&path).to_path_buf(),
+                bytes: digest.bytes,
+                sha256: digest.sha256,
+            })
+            .collect();
+        Ok(Downloaded {
+            time: time.to_string(),
+            grouped: written.grouped,
+            count: None,
+            outputs,
+            severities,
+            skipped: false,
+        })
+    }
+
+    /// Add an archive's staged rows to the --rotate-every output, now that all of it was written.
+    async fn append_rotated(&self, time: &str, written: &mut Written) -> Result<()> {
+        let staged = written
+            .converted
+            .as_mut()
+            .and_then(|converted| converted.staged.take());
+        match (staged, &self.rotator) {
+            (Some(staged), Some(rotator)) => rotator.lock().await.append(time, staged).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn write_archive<S>(
+        &self,
+        time: &str,
+        mut byte_stream: S,
+        archive: Option<&Path>,
+        converted: Option<&Path>,
+        in_memory: bool,
+    ) -> Result<Written>
+    where
+        S: Stream<Item = Result<Bytes, CliError>> + Unpin + Send,
+    {
+        let _permits = self.reserve_files(self.files_per_task()).await;
+        // Anything read back from the archive has to see it decoded.
+        let decompress = self.deflate || converted.is_some();
+        if in_memory {
+            // Small enough for --decompress-to-memory: decode it all before converting.
+            let mut out = Vec::new();
+            let downloaded = self
+                .decode_body(time, &mut byte_stream, &mut out, decompress)
+                .await?;
+            self.check_size(time, downloaded)?;
+            let (converted, grouped) = self.process_archive(Cursor::new(out), converted).await?;
+            return Ok(Written {
+                grouped,
+                archive: None,
+                converted,
+            });
+        }
+
+        let mut file = match archive {
+            Some(archive) => Some(
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(archive)
+                    .await
+                    .map_err(open_error)?,
+            ),
+            None => None,
+        };
+        let mut raw = file
+            .as_mut()
+            .map(|file| HashingWriter::new(BufWriter::new(file), self.manifest.is_some()));
+        // The conversion and grouping read the decoded archive from in-process pipes as it arrives, rather than
+        // re-reading it from disk, so the archive itself only needs to be written if it's being kept.
+        let (to_csv, from_csv) = converted
+            .is_some()
+            .then(|| tokio::io::duplex(DECODE_BUFFER_SIZE))
+            .unzip();
+        let (to_group, from_group) = self
+            .group_by_hour
+            .is_some()
+            .then(|| tokio::io::duplex(DECODE_BUFFER_SIZE))
+            .unzip();
+        let sink = [
+            raw.as_mut().map(|raw| Box::pin(raw) as BoxedWriter),
+            to_csv.map(|pipe| Box::pin(pipe) as BoxedWriter),
+            to_group.map(|pipe| Box::pin(pipe) as BoxedWriter),
+        ]
+        .into_iter()
+        .flatten()
+        .reduce(|primary, secondary| Box::pin(TeeWriter::new(primary, secondary)))
+        .expect("an archive is either kept or converted");
+
+        let decode = async move {
+            let mut sink = sink;
+            let downloaded = self
+                .decode_body(time, &mut byte_stream, &mut sink, decompress)
+                .await?;
+            // Shutting down flushes the archive and ends the pipes' input.
+            sink.shutdown().await?;
+            Ok::<_, anyhow::Error>(downloaded)
+        };
+        let tally_groups = converted.is_none();
+        let convert = async {
+            match from_csv.zip(converted) {
+                Some((from, to)) => Ok(Some(self.convert_to_csv(from, to.to_path_buf()).await?)),
+                None => Ok::<_, anyhow::Error>(None),
+            }
+        };
+        let group = async {
+            match from_group {
+                Some(from) => Ok(Some(self.events_to_json(from, tally_groups).await?)),
+                None => Ok::<_, anyhow::Error>(None),
+            }
+        };
+        let (downloaded, converted, grouped) = tokio::try_join!(decode, convert, group)?;
+        self.check_size(time, downloaded)?;
+
+        Ok(Written {
+            grouped,
+            archive: raw.map(|raw| raw.digest()),
+            converted,
+        })
+    }
+
+    /// Convert and group an archive that was decoded into `source`.
+    async fn process_archive<R>(
+        &self,
+        mut source: R,
+        converted: Option<&Path>,
+    ) -> Result<(Option<Converted>, Option<Vec<u8>>)>
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send,
+    {
+        let converted = match converted {
+            Some(converted) => {
+                source.rewind().await?;
+                Some(
+                    self.convert_to_csv(&mut source, converted.to_path_buf())
+                        .await?,
+                )
+            }
+            None => None,
+        };
+
+        let grouped = match self.group_by_hour {
+            Some(_) => {
+                source.rewind().await?;
+                Some(self.events_to_json(source, converted.is_none()).await?)
+            }
+            None => None,
+        };
+
+        Ok((converted, grouped))
+    }
+
+    fn client(&self) -> &Client {
+        self.http1_client
+            .as_ref()
+            .filter(|_| self.http1_fallback.load(Ordering::Relaxed))
+            .or(self.api_client.as_ref())
+            .expect("API client is built at the start of run")
+    }
+
+    /// Send a request built by `request`, retrying it over HTTP/1.1 if it fails before any response arrives.
+    async fn send<F>(&self, request: F) -> reqwest::Result<reqwest::Response>
+    where
+        F: Fn(&Client) -> reqwest::RequestBuilder,
+    {
+        let result = request(self.client()).send().await;
+        let response = match (result, &self.http1_client) {
+            (Err(e), Some(http1))
+                if (e.is_connect() || e.is_request())
+                    && !self.http1_fallback.load(Ordering::Relaxed) =>
+            {
+                let response = request(http1).send().await?;
+                if !self.http1_fallback.swap(true, Ordering::Relaxed) {
+                    eprintln!(
+                        "Warning: falling back to HTTP/1.1 after a request failed: {}",
+                        e
+                    );
+                }
+                response
+            }
+            (result, _) => result?,
+        };
+        log::debug!("{} negotiated {:?}", response.url(), response.version());
+        Ok(response)
+    }
+
+    /// Wait until `count` more files can be opened without exceeding the open file budget.
+    async fn reserve_files(&self, count: usize) -> Option<SemaphorePermit<'_>> {
+        match &self.open_files {
+            Some(semaphore) => Some(
+                semaphore
+                    .acquire_many(count as u32)
+                    .await
+                    .expect("open file semaphore is never closed"),
+            ),
+            None => None,
+        }
+    }
+
     async fn run(&mut self) -> Result<()> {
+        if self.dump_event_schema {
+            return self.dump_event_schema();
+        }
+        if self.store_in_keyring {
+            return self.store_in_keyring().await;
+        }
         if !self.out.try_exists()? {
             return Err(CliError::MissingDirectory(
                 self.out
@@ -167,68 +1964,1665 @@ impl Cli {
             )
             .into());
         }
+        if self.purge_partial_on_start {
+            for partial in find_partials(&self.out).await? {
+                let path = self.out.join(partial);
+                tokio::fs::remove_file(&path)
+                    .await
+                    .with_context(|| format!("Couldn't remove {}", path.display()))?;
+                println!("Removed stale {}", path.display());
+            }
+        }
+        if self.summary_only {
+            return self.summarize_coverage().await;
+        }
+        self.open_files = Some(Semaphore::new(self.open_file_budget()?));
+        self.event_limiter = self.max_events_per_sec.map(RateLimiter::new);
+        self.rotator = self.rotate_every.map(|every| {
+            tokio::sync::Mutex::new(RotatingCsv::new(
+                self.out.clone(),
+                every,
+                self.manifest.is_some(),
+            ))
+        });
+        if let Some(input) = &self.convert_local {
+            return self.convert_local(input).await;
+        }
+        let token = self.api_token().await?;
+        self.api_client = Some(self.build_api_client(Some(&token), self.force_http1)?);
+        if !self.force_http1 {
+            self.http1_client = Some(self.build_api_client(Some(&token), true)?);
+        }
+        if self.from_index {
+            let urls = archives::fetch(self.client()).await?;
+            log::debug!("Archive index lists {} archives", urls.len());
+            self.archive_urls = Some(urls);
+            self.presigned_client = Some(self.build_api_client(None, self.force_http1)?);
+        }
+        if let Some(id) = self.saved_search {
+            return self.run_saved_search(id).await;
+        }
+        let file_names = self.file_names();
+        let files = file_names.as_ref().unwrap_or(&self.files);
+        if let Some(limit) = self.max_archives.filter(|limit| files.len() > *limit) {
+            let error = CliError::TooManyArchives(files.len(), limit);
+            if !self.yes {
+                return Err(error.into());
+            }
+            eprintln!("Warning: {}; continuing because of --yes", error);
+        }
+        if files.is_empty() {
+            match self.on_empty_window {
+                Reaction::Error => return Err(CliError::EmptyWindow.into()),
+                Reaction::Warn => eprintln!("Warning: {}", CliError::EmptyWindow),
+                Reaction::Ok => {}
+            }
+        }
+        let (throttle, width) = download_pacing(
+            files.len(),
+            self.concurrency,
+            Duration::from_millis(self.throttle_duration),
+        );
+        let mut downloads = std::pin::pin!(futures::StreamExt::buffer_unordered(
+            tokio_stream::iter(files.iter().enumerate().map(|(index, time)| {
+                self.download_file(time.clone())
+                    .map(move |result| (index, result))
+            }),)
+            // TODO: smarter throttling
+            .throttle(throttle),
+            width,
+        ));
+        let mut grouped = match &self.group_by_hour {
+            Some(path) => Some(GroupedJson::create(path).await?),
+            None => None,
+        };
+        let mut zip = match &self.zip {
+            Some(path) => Some(ZipOutput::create(path, self.zip_compression).await?),
+            None => None,
+        };
+        let (mut completed, mut failed) = (0, 0);
+        let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+        let mut manifest: Vec<OutputFile> = vec![];
+        // Hours that were not archived yet, for --partial-window.
+        let mut missing: Vec<String> = vec![];
+        // 404s for --partial-window, only known to be trailing hours once every download finished.
+        let mut not_found: Vec<(usize, anyhow::Error)> = vec![];
+        let mut newest: Option<String> = None;
+        let mut severities = SeverityReport::default();
+        let mut errors = ErrorSuppressor::new(
+            self.min_interval_between_identical_errors
+                .and_then(|window| window.to_std().ok())
+                .unwrap_or(Duration::ZERO),
+        );
+        // Downloads that finished before some earlier hour did, for --ordered-output.
+        let mut pending: BTreeMap<usize, Result<Downloaded>> = BTreeMap::new();
+        let mut next_index = 0;
+        // The first error that stops the run, returned once the outputs below are finalized.
+        let mut outcome = Ok(());
+        'downloads: while let Some((index, result)) = downloads.next().await {
+            let ready = if self.ordered_output {
+                pending.insert(index, result);
+                let mut ready = vec![];
+                while let Some(result) = pending.remove(&next_index) {
+                    ready.push((next_index, result));
+                    next_index += 1;
+                }
+                ready
+            } else {
+                vec![(index, result)]
+            };
+            for (index, result) in ready {
+                match result {
+                    Ok(download) => {
+                        completed += 1;
+                        newest = newest.max(Some(download.time.clone()));
+                        if let (Some(grouped), Some(events)) = (&mut grouped, &download.grouped) {
+                            if let Err(e) = grouped.push(&download.time, events).await {
+                                outcome = Err(e);
+                                break 'downloads;
+                            }
+                        }
+                        if let Some(zip) = &mut zip {
+                            for output in &download.outputs {
+                                if let Err(e) =
+                                    zip.add(&self.out.join(&output.path), &output.path).await
+                                {
+                                    outcome = Err(e.context(format!(
+                                        "Couldn't add {} to the zip",
+                                        output.path.display()
+                                    )));
+                                    break 'downloads;
+                                }
+                            }
+                        }
+                        manifest.extend(download.outputs);
+                        if let Some(histogram) = download.severities {
+                            severities.add(download.time.clone(), histogram, self.json);
+                        }
+                        match download.count {
+                            Some(count) if self.json => {
+                                counts.insert(download.time, count);
+                            }
+                            Some(count) => {
+                                println!("{}\t{}", download.time, count);
+                                counts.insert(download.time, count);
+                            }
+                            None if download.skipped => println!(
+                                "Skipped {}: the local copy is at least as new",
+                                download.time
+                            ),
+                            None => println!("Downloaded {}", download.time),
+                        }
+                    }
+                    Err(e) if self.partial_window && not_archived_yet(&e).is_some() => {
+                        not_found.push((index, e));
+                    }
+                    Err(e) => {
+                        failed += 1;
+                        errors.report(&files[index], &e);
+                        if self.abort_after.is_some_and(|limit| failed >= limit.get()) {
+                            outcome = Err(CliError::TooManyFailures(failed, completed).into());
+                            break 'downloads;
+                        }
+                    }
+                }
+            }
+        }
+        // The index may list hours newer than any this run downloaded.
+        let indexed = self
+            .archive_urls
+            .as_ref()
+            .and_then(|urls| urls.keys().max());
+        let (trailing, gaps) = split_not_archived(not_found, newest.as_ref().max(indexed));
+        missing.extend(trailing);
+        for (index, e) in gaps {
+            failed += 1;
+            errors.report(&files[index], &e);
+        }
+        if outcome.is_ok() && self.abort_after.is_some_and(|limit| failed >= limit.get()) {
+            outcome = Err(CliError::TooManyFailures(failed, completed).into());
+        }
+        errors.finish();
+        // Close every output even when the run stopped early, so what was saved stays readable.
+        let finalized: Result<()> = async {
+            if let Some(grouped) = grouped {
+                grouped.finish().await?;
+            }
+            if let Some(zip) = zip {
+                zip.finish().await?;
+            }
+            if let Some(rotator) = &self.rotator {
+                manifest.extend(rotator.lock().await.finish().await?);
+            }
+            if let Some(path) = &self.manifest {
+                manifest.sort_by(|a, b| (&a.time, &a.path).cmp(&(&b.time, &b.path)));
+                tokio::fs::write(path, serde_json::to_vec_pretty(&manifest)?)
+                    .await
+                    .with_context(|| format!("Couldn't write manifest {}", path.display()))?;
+            }
+            Ok(())
+        }
+        .await;
+        outcome.and(finalized)?;
+        if !missing.is_empty() {
+            missing.sort();
+            eprintln!(
+                "{} hours are not archived yet: {}",
+                missing.len(),
+                missing.join(", ")
+            );
+        }
+        if self.severity_histogram {
+            severities.finish(self.json)?;
+        }
+        if self.verify_window_coverage {
+            self.verify_window_coverage(&missing).await?;
+        }
+        if self.count_only {
+            let total: u64 = counts.values().sum();
+            if self.json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&serde_json::json!({
+                        "hours": counts,
+                        "total": total,
+                    }))?
+                );
+            } else {
+                println!("total\t{}", total);
+            }
+        }
+        Ok(())
+    }
+
+    /// Page through a saved search's results over the window, converting them to CSV.
+    async fn run_saved_search(&self, id: u64) -> Result<()> {
+        let (start, end) = self
+            .window()
+            .expect("--saved-search requires --start and --end");
+        let search = search::SavedSearch::fetch(self.client(), id).await?;
+        let query = search::SearchQuery::new(&search, start, end);
+        let to = self.out.join(format!("search-{}.csv", id));
+        let converted = self
+            .write_events(Box::pin(query.events(self.client())), partial_path(&to))
+            .await?;
+        tokio::fs::rename(partial_path(&to), &to).await?;
+        println!("Saved search {:?} to {}", search.name, to.display());
+        if let Some(histogram) = converted.severities {
+            let mut report = SeverityReport::default();
+            report.add(search.name, histogram, self.json);
+            report.finish(self.json)?;
+        }
+        Ok(())
+    }
+
+    /// Convert every archive under `input` to CSV, mirroring its relative path under the output directory.
+    async fn convert_local(&self, input: &Path) -> Result<()> {
+        let archives = if input.is_dir() {
+            find_archives(input).await?
+        } else {
+            vec![PathBuf::from(input.file_name().with_context(|| {
+                format!("Invalid archive path: {}", input.display())
+            })?)]
+        };
+        let root = if input.is_dir() {
+            input
+        } else {
+            input.parent().unwrap_or(Path::new("."))
+        };
+
+        if self.dry_run_convert {
+            let results = futures::StreamExt::buffer_unordered(
+                tokio_stream::iter(archives.iter().map(|relative| async move {
+                    (relative, self.validate_archive(root.join(relative)).await)
+                })),
+                self.concurrency,
+            )
+            .collect::<Vec<_>>()
+            .await;
+            let mut failed = 0;
+            for (relative, result) in &results {
+                match result {
+                    Ok(events) => println!("Validated {} ({} events)", relative.display(), events),
+                    Err(e) => {
+                        failed += 1;
+                        eprintln!("Error: {:?}", e);
+                    }
+                }
+            }
+            if failed > 0 {
+                return Err(CliError::MalformedArchives(failed, results.len()).into());
+            }
+            return Ok(());
+        }
+
+        let mut severities = SeverityReport::default();
         futures::StreamExt::buffer_unordered(
             tokio_stream::iter(
-                self.file_names()
-                    .as_ref()
-                    .unwrap_or(&self.files)
+                archives
                     .iter()
-                    .map(|time| self.download_file(time.clone())),
-            )
-            // TODO: smarter throttling
-            .throttle(Duration::from_millis(self.throttle_duration)),
+                    .map(|relative| self.convert_archive(root.join(relative), relative)),
+            ),
             self.concurrency,
         )
         .map(|result| match result {
-            Ok(file) => println!("Downloaded {}", file),
+            Ok((file, converted)) => {
+                println!("Converted {}", file.display());
+                if let Some(histogram) = converted.severities {
+                    severities.add(file.display().to_string(), histogram, self.json);
+                }
+            }
             Err(e) => eprintln!("Error: {:?}", e),
         })
         .collect::<Vec<_>>()
         .await;
+        if self.severity_histogram {
+            severities.finish(self.json)?;
+        }
         Ok(())
     }
+
+    async fn convert_archive(
+        &self,
+        path: PathBuf,
+        relative: &Path,
+    ) -> Result<(PathBuf, Converted)> {
+        let mut to = self.out.join(archive_stem(relative)).into_os_string();
+        to.push(".csv");
+        let to = PathBuf::from(to);
+        if let Some(parent) = to.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let _permits = self.reserve_files(self.files_per_task()).await;
+        let file = File::open(&path)
+            .await
+            .map_err(open_error)
+            .with_context(|| format!("Couldn't open {}", path.display()))?;
+        let partial = partial_path(&to);
+        let converted = if is_ndjson(&path) {
+            self.convert_ndjson_to_csv(file, partial.clone()).await
+        } else if is_gzip(&path) {
+            self.convert_to_csv(GzipReadDecoder::new(BufReader::new(file)), partial.clone())
+                .await
+        } else {
+            self.convert_to_csv(file, partial.clone()).await
+        };
+        // As in save_archive, a failed conversion never leaves a truncated CSV in place.
+        match converted {
+            Ok(converted) => {
+                tokio::fs::rename(&partial, &to).await?;
+                Ok((to, converted))
+            }
+            Err(e) => {
+                tokio::fs::remove_file(&partial).await.ok();
+                Err(e.context(format!("Failed to convert {}", path.display())))
+            }
+        }
+    }
+
+    /// Run an archive through the same readers as [`Run::convert_archive`], returning how many events it holds.
+    async fn validate_archive(&self, path: PathBuf) -> Result<u64> {
+        let _permits = self.reserve_files(1).await;
+        let file = File::open(&path)
+            .await
+            .map_err(open_error)
+            .with_context(|| format!("Couldn't open {}", path.display()))?;
+        if is_ndjson(&path) {
+            self.validate_events(ndjson_events(file)).await
+        } else if is_gzip(&path) {
+            self.validate_events(tsv_events(
+                GzipReadDecoder::new(BufReader::new(file)),
+                self.strict_conversion,
+            ))
+            .await
+        } else {
+            self.validate_events(tsv_events(file, self.strict_conversion))
+                .await
+        }
+        .with_context(|| format!("{} doesn't parse cleanly", path.display()))
+    }
+
+    async fn convert_to_csv<R>(&self, from: R, to: PathBuf) -> Result<Converted>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        self.write_events(tsv_events(from, self.strict_conversion), to)
+            .await
+    }
+
+    /// Replay events previously saved as newline-delimited JSON through the conversion pipeline.
+    async fn convert_ndjson_to_csv<R>(&self, from: R, to: PathBuf) -> Result<Converted>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        self.write_events(ndjson_events(from), to).await
+    }
+
+    /// Wait for --max-events-per-sec. Only [`Run::write_events`] calls this, so an archive that is
+    /// also grouped isn't paced twice.
+    async fn pace_event(&self) {
+        if let Some(limiter) = &self.event_limiter {
+            limiter.pace().await;
+        }
+    }
+
+    /// Apply the per-event rewrites requested on the command line.
+    ///
+    /// An archive may be read by more than one consumer, so only the one that `tally`s counts
+    /// canonicalized messages, warns about invalid IPs and raises --alert-on-severity. Dry runs
+    /// don't tally at all.
+    fn prepare_event(&self, event: &mut Event, tally: bool) -> Result<()> {
+        if let SourceIp::Raw(raw) = &event.source_ip {
+            match self.validate_ip {
+                IpValidation::Strict => {
+                    return Err(CliError::InvalidSourceIp(raw.clone(), event.id).into())
+                }
+                IpValidation::Lenient if !tally => {}
+                IpValidation::Lenient => eprintln!(
+                    "Warning: {}",
+                    CliError::InvalidSourceIp(raw.clone(), event.id)
+                ),
+            }
+        }
+        if let Some(label) = self
+            .source_map
+            .as_ref()
+            .and_then(|map| map.get(&event.source_id))
+        {
+            event.source_name.clone_from(label);
+        }
+        if self.canonicalize_messages {
+            if let Some(message) = collapse_whitespace(&event.message) {
+                event.message = message;
+                if tally {
+                    self.canonicalized.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        if tally
+            && self.alert_on_severity.is_some_and(|level| {
+                Severity::from_name(&event.severity_name).is_some_and(|severity| severity <= level)
+            })
+        {
+            self.alerted.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Convert a decoded archive into a JSON array of its events, tallying them if nothing else
+    /// reads the archive (see [`Run::prepare_event`]).
+    async fn events_to_json<R>(&self, from: R, tally: bool) -> Result<Vec<u8>>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut records = AsyncReaderBuilder::new()
+            .has_headers(false)
+            .delimiter(b'\t')
+            .create_deserializer(from)
+            .into_deserialize::<Event>();
+        let mut json = vec![b'['];
+        while let Some(record) = records.next().await {
+            let mut event: Event = record?;
+            self.prepare_event(&mut event, tally)?;
+            if json.len() > 1 {
+                json.push(b',');
+            }
+            serde_json::to_writer(&mut json, &event)?;
+        }
+        json.push(b']');
+        Ok(json)
+    }
+
+    /// Read and prepare every event as [`Run::write_events`] would, but discard them.
+    async fn validate_events<S, E>(&self, mut events: S) -> Result<u64>
+    where
+        S: Stream<Item = Result<Event, E>> + Unpin,
+        anyhow::Error: From<E>,
+    {
+        let mut count: u64 = 0;
+        while let Some(record) = events.next().await {
+            let mut event: Event = record
+                .map_err(anyhow::Error::from)
+                .with_context(|| format!("Event {} is malformed", count + 1))?;
+            self.prepare_event(&mut event, false)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    async fn write_events<S, E>(&self, mut events: S, to: PathBuf) -> Result<Converted>
+    where
+        S: Stream<Item = Result<Event, E>> + Unpin,
+        anyhow::Error: From<E>,
+    {
+        let mut writer = match &self.rotator {
+            // Staged rather than written straight to the rotating output, so an archive that fails
+            // partway (and may be retried) leaves nothing there.
+            Some(_) => {
+                let staged = tempfile::tempfile_in(&self.out)
+                    .map_err(open_error)
+                    .context("Couldn't create a temporary file for --rotate-every")?;
+                CsvOutput::Staged(Box::new(
+                    AsyncWriterBuilder::new()
+                        .has_headers(false)
+                        .create_serializer(File::from_std(staged)),
+                ))
+            }
+            None => {
+                let file = tokio::fs::File::create(to).await.map_err(open_error)?;
+                CsvOutput::File(Box::new(
+                    AsyncWriterBuilder::new()
+                        .create_serializer(HashingWriter::new(file, self.manifest.is_some())),
+                ))
+            }
+        };
+        let mut count: u64 = 0;
+        let mut severities = self.severity_histogram.then(Histogram::new);
+
+        while let Some(record) = events.next().await {
+            self.pace_event().await;
+            let mut event: Event = record?;
+            self.prepare_event(&mut event, true)?;
+            if let Some(severities) = &mut severities {
+                // Events are mostly of a handful of severities, so avoid allocating a key per event.
+                match severities.get_mut(&event.severity_name) {
+                    Some(tally) => *tally += 1,
+                    None => {
+                        severities.insert(event.severity_name.clone(), 1);
+                    }
+                }
+            }
+            writer.serialize(event).await?;
+            count += 1;
+            if self
+                .checkpoint_interval
+                .is_some_and(|interval| count.is_multiple_of(interval))
+            {
+                writer.flush().await?;
+            }
+        }
+
+        writer.flush().await?;
+        let (digest, staged) = match writer {
+            CsvOutput::File(writer) => (Some(writer.into_inner().await?.digest()), None),
+            CsvOutput::Staged(writer) => (None, Some(writer.into_inner().await?)),
+        };
+        Ok(Converted {
+            digest,
+            severities,
+            staged,
+        })
+    }
 }
 
 #[derive(Error, Debug)]
 enum CliError {
+    #[error("An API token is required; pass --api-token or set PAPERTRAIL_API_TOKEN")]
+    MissingToken,
+    #[error("Couldn't access the OS keyring")]
+    Keyring(#[source] keyring::Error),
+    #[error("No API token in the OS keyring for service {0}, account {1}; save one with --store-in-keyring")]
+    MissingKeyringToken(String, String),
     #[error("Couldn't find directory: {0}")]
     MissingDirectory(String),
     #[error("Failed to download {0}: {1}")]
     BadResponse(String, StatusCode),
+    #[error("Connection dropped while downloading {0}")]
+    InterruptedBody(String, #[source] reqwest::Error),
+    #[error("An open file limit of {0} is too low for --concurrency {1}")]
+    OpenFileLimit(usize, usize),
+    #[error("Too many open files; lower --concurrency or --max-open-files")]
+    TooManyOpenFiles(#[source] std::io::Error),
+    #[error("Archive {0} is only {1} bytes (expected at least {2})")]
+    ArchiveTooSmall(String, u64, u64),
+    #[error("Filter command failed for {0}: {1}")]
+    FilterFailed(String, ExitStatus),
+    #[error("Malformed source IP {0:?} in event {1}")]
+    InvalidSourceIp(String, u128),
+    #[error("No archives to download")]
+    EmptyWindow,
+    #[error("Aborted after {0} failed downloads ({1} completed)")]
+    TooManyFailures(usize, usize),
+    #[error("Record {record} (line {line}) has an invalid {field} {value:?}: {reason}\n  {raw}")]
+    MalformedRecord {
+        record: u64,
+        line: u64,
+        field: &'static str,
+        value: String,
+        reason: String,
+        raw: String,
+    },
+    #[error("{0} archives requested, more than --max-archives {1}")]
+    TooManyArchives(usize, usize),
+    #[error("Gave up waiting for space in {0}")]
+    DiskFull(String),
+    #[error("{0} hours of the window have no output: {1}")]
+    CoverageGaps(usize, String),
+    #[error("{0} of {1} archives failed to parse")]
+    MalformedArchives(usize, usize),
 }
 
-async fn convert_to_csv(from: File, to: PathBuf) -> Result<()> {
-    let reader = AsyncReaderBuilder::new()
-        .has_headers(false)
-        .delimiter(b'\t')
-        .create_deserializer(from);
-    let file = tokio::fs::File::create(to).await?;
-    let mut writer = AsyncWriterBuilder::new().create_serializer(file);
-    let mut records = reader.into_deserialize::<Event>();
+/// Parse a duration like `90s`, `30m`, `36h` or `7d`.
+fn parse_duration(duration: &str) -> Result<TimeDelta> {
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .with_context(|| format!("Missing unit in {:?}; use s, m, h or d", duration))?;
+    let (amount, unit) = duration.split_at(split);
+    let amount: i64 = amount
+        .parse()
+        .with_context(|| format!("Invalid duration {:?}", duration))?;
+    match unit {
+        "s" => TimeDelta::try_seconds(amount),
+        "m" => TimeDelta::try_minutes(amount),
+        "h" => TimeDelta::try_hours(amount),
+        "d" => TimeDelta::try_days(amount),
+        _ => anyhow::bail!(
+            "Unknown unit {:?} in {:?}; use s, m, h or d",
+            unit,
+            duration
+        ),
+    }
+    .with_context(|| format!("Duration {:?} is too long", duration))
+}
 
-    while let Some(record) = records.next().await {
-        writer.serialize(record?).await?;
+fn parse_resolve_override(entry: &str) -> Result<ResolveOverride> {
+    let invalid = || format!("Invalid --resolve {}, expected HOST:PORT:ADDR", entry);
+    let mut parts = entry.splitn(3, ':');
+    let (Some(host), Some(port), Some(addr)) = (parts.next(), parts.next(), parts.next()) else {
+        anyhow::bail!(invalid());
+    };
+    let port: u16 = port.parse().with_context(invalid)?;
+    let ip: IpAddr = addr
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .with_context(invalid)?;
+    if host.is_empty() {
+        anyhow::bail!(invalid());
     }
+    Ok(ResolveOverride {
+        host: host.to_string(),
+        addr: SocketAddr::new(ip, port),
+    })
+}
 
-    writer.flush().await?;
-    Ok(())
+/// Copy a response body into `to`, returning how many bytes were received.
+async fn copy_body<S, W>(byte_stream: &mut S, to: &mut W) -> Result<u64>
+where
+    S: Stream<Item = Result<Bytes, CliError>> + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut received: u64 = 0;
+    while let Some(item) = byte_stream.next().await {
+        let chunk = item?;
+        received += chunk.len() as u64;
+        to.write_all(&chunk).await?;
+    }
+    Ok(received)
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Turn the OS's "too many open files" error into one that says what to do about it.
+fn open_error(error: std::io::Error) -> anyhow::Error {
+    #[cfg(unix)]
+    if error.raw_os_error() == Some(libc::EMFILE) {
+        return CliError::TooManyOpenFiles(error).into();
+    }
+    error.into()
+}
+
+/// Whether `error` was caused by a write to a full disk.
+fn is_disk_full(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::StorageFull)
+    })
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`, if it can be checked.
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: statvfs is plain data, so all zeroes is a valid value for statvfs to overwrite.
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid C string and `stats` is a valid, writable statvfs.
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    // The field types differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Some((stats.f_bavail as u64).saturating_mul(stats.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Try to raise the soft limit on open files to `wanted`, returning how many descriptors may be used.
+#[cfg(unix)]
+fn raise_open_file_limit(wanted: usize) -> usize {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid, writable rlimit for getrlimit to fill in.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return wanted;
+    }
+    let current = usize::try_from(limit.rlim_cur).unwrap_or(usize::MAX);
+    if current >= wanted {
+        return wanted;
+    }
+    let raised = libc::rlimit {
+        rlim_cur: (wanted as libc::rlim_t).min(limit.rlim_max),
+        rlim_max: limit.rlim_max,
+    };
+    // SAFETY: `raised` is a valid rlimit that doesn't exceed the hard limit.
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } != 0 {
+        return current;
+    }
+    usize::try_from(raised.rlim_cur).unwrap_or(usize::MAX)
+}
+
+#[cfg(not(unix))]
+fn raise_open_file_limit(wanted: usize) -> usize {
+    wanted
+}
+
+/// The delay between starting downloads and how many may run at once, for `files` downloads.
+fn download_pacing(files: usize, concurrency: usize, throttle: Duration) -> (Duration, usize) {
+    // When every file fits in the first batch there is nothing to stagger, so start them all at once.
+    let throttle = if files > concurrency {
+        throttle
+    } else {
+        Duration::ZERO
+    };
+    (throttle, concurrency.min(files).max(1))
+}
+
+/// A uniformly random duration in `low..=high`, or `low` if the range is empty.
+fn random_between(low: Duration, high: Duration) -> Duration {
+    let span = high.saturating_sub(low).as_nanos();
+    low + Duration::from_nanos(fastrand::u64(0..=u64::try_from(span).unwrap_or(u64::MAX)))
+}
+
+/// The hour of a download that failed because its archive doesn't exist (yet).
+fn not_archived_yet(error: &anyhow::Error) -> Option<&str> {
+    match error.downcast_ref::<CliError>() {
+        Some(CliError::BadResponse(time, StatusCode::NOT_FOUND)) => Some(time),
+        _ => None,
+    }
+}
+
+/// Split the 404s of a --partial-window run into the hours after `newest`, the latest hour known to
+/// be archived, which just aren't archived yet, and the rest, which are real gaps.
+fn split_not_archived(
+    not_found: Vec<(usize, anyhow::Error)>,
+    newest: Option<&String>,
+) -> (Vec<String>, Vec<(usize, anyhow::Error)>) {
+    let (trailing, gaps): (Vec<_>, Vec<_>) = not_found.into_iter().partition(|(_, e)| {
+        not_archived_yet(e).is_some_and(|time| newest.is_some_and(|newest| time > newest.as_str()))
+    });
+    let trailing = trailing
+        .iter()
+        .filter_map(|(_, e)| not_archived_yet(e).map(str::to_string))
+        .collect();
+    (trailing, gaps)
+}
+
+fn is_interrupted_body(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<CliError>(),
+        Some(CliError::InterruptedBody(..))
+    )
+}
+
+/// Where a file is written before being renamed into place once complete.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".partial");
+    PathBuf::from(name)
+}
+
+/// Print one bar per severity, scaled so the most common fills the width.
+fn print_histogram(histogram: &Histogram) {
+    const WIDTH: u64 = 40;
+    let max = histogram.values().copied().max().unwrap_or(0).max(1);
+    let label = histogram.keys().map(String::len).max().unwrap_or(0);
+    for (severity, count) in histogram {
+        let bar = (count * WIDTH).div_ceil(max) as usize;
+        println!("  {:label$} {} {}", severity, "#".repeat(bar), count);
+    }
+}
+
+/// Events from a decoded Papertrail TSV archive. When `strict`, a row that doesn't parse is reported with its position, the
+/// offending field and the raw line.
+fn tsv_events<'a, R>(
+    from: R,
+    strict: bool,
+) -> Pin<Box<dyn Stream<Item = Result<Event>> + Send + 'a>>
+where
+    R: AsyncRead + Unpin + Send + 'a,
+{
+    let mut builder = AsyncReaderBuilder::new();
+    builder.has_headers(false).delimiter(b'\t');
+    if !strict {
+        return Box::pin(
+            builder
+                .create_deserializer(from)
+                .into_deserialize::<Event>()
+                .map(|record| Ok(record?)),
+        );
+    }
+    // Flexible so rows with the wrong number of fields reach malformed_record too, rather than
+    // failing with the CSV reader's own error.
+    builder.flexible(true);
+    Box::pin(builder.create_reader(from).into_records().map(|record| {
+        let record = record?;
+        if record.len() != EVENT_FIELDS.len() {
+            let reason = format!(
+                "expected {} fields, found {}",
+                EVENT_FIELDS.len(),
+                record.len()
+            );
+            return Err(malformed_record(&record, None, reason).into());
+        }
+        record.deserialize::<Event>(None).map_err(|e| {
+            let (field, reason) = match e.kind() {
+                csv_async::ErrorKind::Deserialize { err, .. } => {
+                    (err.field(), err.kind().to_string())
+                }
+                _ => (None, e.to_string()),
+            };
+            malformed_record(&record, field, reason).into()
+        })
+    }))
+}
+
+/// A column of a Papertrail archive, which is also a CSV column and JSON key of converted output.
+#[derive(serde::Serialize)]
+struct EventField {
+    name: &'static str,
+    /// How the tool parses it.
+    #[serde(rename = "type")]
+    rust_type: &'static str,
+    /// A SQL column type that holds every value it can take.
+    sql_type: &'static str,
+}
+
+/// The columns of a Papertrail archive, in order. Keep in step with [`Event`].
+const EVENT_FIELDS: [EventField; 10] = [
+    EventField {
+        name: "id",
+        rust_type: "u128",
+        sql_type: "NUMERIC(39)",
+    },
+    EventField {
+        name: "generated_at",
+        rust_type: "string",
+        sql_type: "TEXT",
+    },
+    EventField {
+        name: "received_at",
+        rust_type: "string",
+        sql_type: "TEXT",
+    },
+    EventField {
+        name: "source_id",
+        rust_type: "u32",
+        sql_type: "BIGINT",
+    },
+    EventField {
+        name: "source_name",
+        rust_type: "string",
+        sql_type: "TEXT",
+    },
+    EventField {
+        name: "source_ip",
+        rust_type: "ip address (or raw string with --validate-ip lenient)",
+        sql_type: "TEXT",
+    },
+    EventField {
+        name: "facility_name",
+        rust_type: "string",
+        sql_type: "TEXT",
+    },
+    EventField {
+        name: "severity_name",
+        rust_type: "string",
+        sql_type: "TEXT",
+    },
+    EventField {
+        name: "program",
+        rust_type: "string",
+        sql_type: "TEXT",
+    },
+    EventField {
+        name: "message",
+        rust_type: "string",
+        sql_type: "TEXT",
+    },
+];
+
+fn malformed_record(
+    record: &csv_async::StringRecord,
+    field: Option<u64>,
+    reason: String,
+) -> CliError {
+    let position = record.position();
+    let field = field.and_then(|field| usize::try_from(field).ok());
+    CliError::MalformedRecord {
+        record: position.map_or(0, |position| position.record() + 1),
+        line: position.map_or(0, |position| position.line()),
+        field: field
+            .and_then(|field| EVENT_FIELDS.get(field))
+            .map_or("row", |field| field.name),
+        value: field
+            .and_then(|field| record.get(field))
+            .unwrap_or_default()
+            .to_string(),
+        reason,
+        raw: record.iter().collect::<Vec<_>>().join("\t"),
+    }
+}
+
+/// Events saved as newline-delimited JSON, skipping blank lines. Each line is either an event as
+/// this tool writes it or one captured from the search API.
+fn ndjson_events<'a, R>(from: R) -> impl Stream<Item = Result<Event>> + Unpin + 'a
+where
+    R: AsyncRead + Unpin + Send + 'a,
+{
+    LinesStream::new(BufReader::new(from).lines())
+        .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(|line| {
+            let line = line?;
+            match serde_json::from_str::<Event>(&line) {
+                Ok(event) => Ok(event),
+                // Report the error for our own shape if the line is neither.
+                Err(error) => match serde_json::from_str::<search::SearchEvent>(&line) {
+                    Ok(event) => event.try_into(),
+                    Err(_) => Err(error.into()),
+                },
+            }
+        })
+}
+
+/// Whether a downloaded file holds any (decoded) data.
+async fn has_content(path: &Path) -> Result<bool> {
+    let file = File::open(path).await?;
+    if is_gzip(path) {
+        let mut decoder = GzipReadDecoder::new(BufReader::new(file));
+        Ok(decoder.read(&mut [0]).await? > 0)
+    } else {
+        Ok(file.metadata().await?.len() > 0)
+    }
+}
+
+fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+fn is_ndjson(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext == "ndjson" || ext == "jsonl")
+}
+
+/// Strip the `.tsv` / `.tsv.gz` / `.ndjson` / `.jsonl` extension from an archive path, leaving
+/// any other dots in the file name alone.
+fn archive_stem(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let stem = [".tsv.gz", ".tsv", ".ndjson", ".jsonl"]
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .unwrap_or(&name);
+    path.with_file_name(stem)
+}
+
+/// Recursively collect the paths of archives under `root`, relative to it. Non-archive files are skipped.
+async fn find_archives(root: &Path) -> Result<Vec<PathBuf>> {
+    find_files(root, |name| {
+        name.ends_with(".tsv") || name.ends_with(".tsv.gz") || is_ndjson(Path::new(name))
+    })
+    .await
+}
+
+/// Recursively collect the paths of `.partial` files this tool leaves under `root`, relative to it.
+async fn find_partials(root: &Path) -> Result<Vec<PathBuf>> {
+    find_files(root, |name| {
+        name.strip_suffix(".partial").is_some_and(|name| {
+            ARCHIVE_EXTENSIONS
+                .iter()
+                .any(|ext| name.ends_with(&format!(".{}", ext)))
+        })
+    })
+    .await
+}
+
+/// Recursively collect the paths of files under `root` whose names satisfy `keep`, relative to it.
+async fn find_files(root: &Path, keep: impl Fn(&str) -> bool) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(root.join(&dir))
+            .await
+            .with_context(|| format!("Couldn't read directory {}", root.join(&dir).display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let relative = dir.join(entry.file_name());
+            if entry.file_type().await?.is_dir() {
+                dirs.push(relative);
+            } else if entry.file_name().to_str().is_some_and(&keep) {
+                files.push(relative);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
 }
 
-fn api_client_from_token(token: &str) -> Result<Client> {
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "X-Papertrail-Token",
-        reqwest::header::HeaderValue::from_str(token).context("Invalid API token")?,
-    );
-    Client::builder()
-        .default_headers(headers)
-        .build()
-        .context("Couldn't build client")
+fn source_map_from_file(path: &str) -> Result<SourceMap> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_path(path)
+        .with_context(|| format!("Couldn't open source map {}", path))?;
+    reader
+        .deserialize::<(u32, String)>()
+        .map(|entry| entry.with_context(|| format!("Invalid entry in source map {}", path)))
+        .collect()
+}
+
+/// Collapse each run of whitespace in `message` to one space, or `None` if there is nothing to collapse.
+fn collapse_whitespace(message: &str) -> Option<String> {
+    // Most messages have nothing to collapse, so check before allocating.
+    if !message.contains("  ") && !message.contains(|c: char| c.is_whitespace() && c != ' ') {
+        return None;
+    }
+    let mut collapsed = String::with_capacity(message.len());
+    let mut in_whitespace = false;
+    for c in message.chars() {
+        if c.is_whitespace() {
+            if !in_whitespace {
+                collapsed.push(' ');
+            }
+            in_whitespace = true;
+        } else {
+            collapsed.push(c);
+            in_whitespace = false;
+        }
+    }
+    Some(collapsed)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv().ok();
-    Cli::parse().run().await?;
+    env_logger::init();
+    let mut run = Run::new(Cli::parse());
+    run.run().await?;
+    if run.canonicalize_messages {
+        eprintln!(
+            "Canonicalized whitespace in {} messages",
+            run.canonicalized.load(Ordering::Relaxed)
+        );
+    }
+    if run.alerted.load(Ordering::Relaxed) {
+        std::process::exit(ALERT_EXIT_CODE);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(
+            ["download_papertrail", "--api-token", "x"]
+                .iter()
+                .chain(args),
+        )
+    }
+
+    fn cli(args: &[&str]) -> Cli {
+        parse(args).expect("test arguments parse")
+    }
+
+    fn run(args: &[&str]) -> Run {
+        Run::new(cli(args))
+    }
+
+    /// Three archive rows, one of them with a message that needs quoting in CSV.
+    const ARCHIVE: &str = "\
+1001\t2024-01-02T05:00:01Z\t2024-01-02T05:00:02Z\t42\tweb-1\t10.0.0.1\tUser\tInfo\tapp\thello, \"world\"
+1002\t2024-01-02T05:10:01Z\t2024-01-02T05:10:02Z\t43\tweb-2\t10.0.0.2\tUser\tError\tapp\tboom
+1003\t2024-01-02T05:20:01Z\t2024-01-02T05:20:02Z\t42\tweb-1\t10.0.0.1\tUser\tWarning\tapp\tdone
+";
+
+    async fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = async_compression::tokio::write::GzipEncoder::new(Vec::new());
+        encoder.write_all(data).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        encoder.into_inner()
+    }
+
+    /// Any `reqwest::Error` will do to stand for a dropped connection.
+    fn reqwest_error() -> reqwest::Error {
+        Client::new().get("not a url").build().unwrap_err()
+    }
+
+    fn disk_full() -> anyhow::Error {
+        std::io::Error::from(std::io::ErrorKind::StorageFull).into()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn enospc_counts_as_disk_full() {
+        let error = anyhow::Error::from(std::io::Error::from_raw_os_error(libc::ENOSPC))
+            .context("Couldn't write archive");
+        assert!(is_disk_full(&error));
+        assert!(!is_disk_full(&anyhow::anyhow!("No space left")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disk_full_waits_for_space_then_retries() {
+        let mut run = run(&["--throttle-on-disk-full", "1m"]);
+        static CHECKS: AtomicU32 = AtomicU32::new(0);
+        run.free_space = |_| {
+            let full = CHECKS.fetch_add(1, Ordering::Relaxed) < 2;
+            Some(if full { 0 } else { DISK_SPACE_TO_RESUME })
+        };
+        let attempts = AtomicU32::new(0);
+        let start = Instant::now();
+        let result = run
+            .retry_download("2024-01-01-00", |_| {
+                let attempt = attempts.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    match attempt {
+                        0 => Err(disk_full()),
+                        _ => Ok(()),
+                    }
+                }
+            })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        assert_eq!(start.elapsed(), DISK_SPACE_POLL_INTERVAL * 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disk_full_gives_up_when_freeing_the_partial_makes_room() {
+        // Deleting the failed attempt's output leaves enough free space to pass the check every time,
+        // but the archive never fits.
+        let mut run = run(&["--throttle-on-disk-full", "1m"]);
+        run.free_space = |_| Some(DISK_SPACE_TO_RESUME * 2);
+        let attempts = AtomicU32::new(0);
+        let start = Instant::now();
+        let error = run
+            .retry_download("2024-01-01-00", |_| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async { Err::<(), _>(disk_full()) }
+            })
+            .await
+            .expect_err("the disk never has room");
+        assert!(matches!(
+            error.downcast_ref::<CliError>(),
+            Some(CliError::DiskFull(_))
+        ));
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+        assert_eq!(attempts.load(Ordering::Relaxed), 13);
+
+        // Later downloads fail without waiting again.
+        let start = Instant::now();
+        let result = run
+            .retry_download("2024-01-01-01", |_| async { Err::<(), _>(disk_full()) })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn disk_full_is_an_error_without_the_option() {
+        let run = run(&[]);
+        let attempts = AtomicU32::new(0);
+        let result = run
+            .retry_download("2024-01-01-00", |_| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async { Err::<(), _>(disk_full()) }
+            })
+            .await;
+        assert!(is_disk_full(&result.expect_err("nothing retries it")));
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn rotated_rows_are_only_written_once_appended() {
+        let out = tempfile::tempdir().unwrap();
+        let dir = out.path().to_str().unwrap();
+        let plain = run(&["--csv", "-o", dir]);
+        plain
+            .convert_to_csv(ARCHIVE.as_bytes(), out.path().join("plain.csv"))
+            .await
+            .unwrap();
+        let expected = std::fs::read_to_string(out.path().join("plain.csv")).unwrap();
+
+        let mut rotated = run(&["--csv", "--rotate-every", "2", "-o", dir]);
+        rotated.rotator = Some(tokio::sync::Mutex::new(RotatingCsv::new(
+            out.path().to_path_buf(),
+            2,
+            true,
+        )));
+        let converted = rotated
+            .convert_to_csv(ARCHIVE.as_bytes(), out.path().join("unused.csv"))
+            .await
+            .unwrap();
+        assert!(converted.digest.is_none());
+        assert!(!out.path().join("events-00000.csv").exists());
+
+        let rotator = rotated.rotator.as_ref().unwrap();
+        rotator
+            .lock()
+            .await
+            .append("2024-01-02-05", converted.staged.unwrap())
+            .await
+            .unwrap();
+        let first = std::fs::read_to_string(out.path().join("events-00000.csv")).unwrap();
+        let second = std::fs::read_to_string(out.path().join("events-00001.csv")).unwrap();
+        let (header, rest) = second.split_once('\n').unwrap();
+        assert!(expected.starts_with(header));
+        assert_eq!(first.lines().count(), 3);
+        assert_eq!(first.clone() + rest, expected);
+
+        let outputs = rotator.lock().await.finish().await.unwrap();
+        assert_eq!(
+            outputs
+                .iter()
+                .map(|output| (
+                    output.time.as_str(),
+                    output.path.to_str().unwrap(),
+                    output.bytes
+                ))
+                .collect::<Vec<_>>(),
+            [
+                ("2024-01-02-05", "events-00000.csv", first.len() as u64),
+                ("2024-01-02-05", "events-00001.csv", second.len() as u64),
+            ]
+        );
+        assert!(outputs.iter().all(|output| output.sha256.is_some()));
+    }
+
+    #[tokio::test]
+    async fn interrupted_body_leaves_no_output() {
+        let archive = gzip(ARCHIVE.as_bytes()).await;
+        let (head, _) = archive.split_at(archive.len() / 2);
+        for csv in [false, true] {
+            let out = tempfile::tempdir().unwrap();
+            let dir = out.path().to_str().unwrap();
+            let args = ["-o", dir, "--csv"];
+            let run = run(&args[..if csv { 3 } else { 2 }]);
+            let body = tokio_stream::iter(vec![
+                Ok(Bytes::copy_from_slice(head)),
+                Err(CliError::InterruptedBody(
+                    "2024-01-02-05".to_string(),
+                    reqwest_error(),
+                )),
+            ]);
+            let (archive, converted) = if csv {
+                (None, Some(out.path().join("2024-01-02-05.csv")))
+            } else {
+                (Some(out.path().join("2024-01-02-05.tsv.gz")), None)
+            };
+            let Err(error) = run
+                .save_archive("2024-01-02-05", body, archive, converted, false)
+                .await
+            else {
+                panic!("the body was cut off");
+            };
+            assert!(is_interrupted_body(&error));
+            // Neither the partial output nor a renamed one is left behind.
+            assert_eq!(std::fs::read_dir(out.path()).unwrap().count(), 0);
+        }
+    }
+
+    /// Parse a single archive row with `source_ip` in place of the usual address.
+    async fn event_with_source_ip(source_ip: &str) -> Event {
+        let row = format!(
+            "1001\t2024-01-02T05:00:01Z\t2024-01-02T05:00:02Z\t42\tweb-1\t{}\tUser\tInfo\tapp\thello\n",
+            source_ip
+        );
+        let mut events = tsv_events(row.as_bytes(), true);
+        events.next().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn ipv6_source_ips_parse_as_addresses() {
+        for addr in ["2001:db8::1", "::1", "fe80::1ff:fe23:4567:890a"] {
+            let event = event_with_source_ip(addr).await;
+            assert_eq!(event.source_ip, SourceIp::Addr(addr.parse().unwrap()));
+        }
+        let event = event_with_source_ip("10.0.0.1").await;
+        assert_eq!(event.source_ip, SourceIp::Addr(IpAddr::from([10, 0, 0, 1])));
+    }
+
+    #[tokio::test]
+    async fn invalid_source_ips_follow_validate_ip() {
+        let mut event = event_with_source_ip("not-an-ip").await;
+        assert_eq!(event.source_ip, SourceIp::Raw("not-an-ip".to_string()));
+
+        let error = run(&["--validate-ip", "strict"])
+            .prepare_event(&mut event, true)
+            .expect_err("strict rejects it");
+        assert!(matches!(
+            error.downcast_ref::<CliError>(),
+            Some(CliError::InvalidSourceIp(raw, 1001)) if raw == "not-an-ip"
+        ));
+
+        run(&["--validate-ip", "lenient"])
+            .prepare_event(&mut event, true)
+            .expect("lenient lets it through");
+        assert_eq!(event.source_ip.to_string(), "not-an-ip");
+    }
+
+    #[tokio::test]
+    async fn streamed_conversion_matches_rewound_file() {
+        let out = tempfile::tempdir().unwrap();
+        let dir = out.path().to_str().unwrap();
+        let grouped = out.path().join("grouped.json");
+        let run = run(&[
+            "--csv",
+            "-d",
+            "--group-by-hour",
+            grouped.to_str().unwrap(),
+            "-o",
+            dir,
+        ]);
+        let archive = gzip(ARCHIVE.repeat(50).as_bytes()).await;
+        // Small chunks, so decoding and converting interleave.
+        let body = tokio_stream::iter(
+            archive
+                .chunks(64)
+                .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+                .collect::<Vec<_>>(),
+        );
+        let streamed = out.path().join("streamed.csv");
+        let written = run
+            .write_archive("2024-01-02-05", body, None, Some(&streamed), false)
+            .await
+            .unwrap();
+
+        let rewound = out.path().join("rewound.csv");
+        let (_, grouped) = run
+            .process_archive(Cursor::new(ARCHIVE.repeat(50)), Some(&rewound))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read(&streamed).unwrap(),
+            std::fs::read(&rewound).unwrap()
+        );
+        assert_eq!(written.grouped, grouped);
+    }
+
+    #[test]
+    fn deflate_keeps_the_decoded_archive_alongside_the_csv() {
+        let stem = Path::new("out/2024-01-02-05");
+        let (archive, converted) = cli(&["--csv"]).output_paths("2024-01-02-05", stem);
+        assert_eq!(archive, None);
+        assert_eq!(converted, Some(stem.with_extension("csv")));
+
+        let (archive, converted) = cli(&["--csv", "-d"]).output_paths("2024-01-02-05", stem);
+        assert_eq!(archive, Some(stem.with_extension("tsv")));
+        assert_eq!(converted, Some(stem.with_extension("csv")));
+
+        let (archive, _) = cli(&["-d"]).output_paths("2024-01-02-05", stem);
+        assert_eq!(archive, Some(stem.with_extension("tsv")));
+        let (archive, _) = cli(&[]).output_paths("2024-01-02-05", stem);
+        assert_eq!(archive, Some(stem.with_extension("tsv.gz")));
+        assert!(parse(&["--csv", "-d", "--decompress-to-memory", "1000"]).is_err());
+    }
+
+    #[test]
+    fn small_jobs_start_every_download_at_once() {
+        let throttle = Duration::from_millis(200);
+        assert_eq!(download_pacing(3, 32, throttle), (Duration::ZERO, 3));
+        assert_eq!(download_pacing(32, 32, throttle), (Duration::ZERO, 32));
+        assert_eq!(download_pacing(33, 32, throttle), (throttle, 32));
+        // buffer_unordered never starts anything with a width of 0.
+        assert_eq!(download_pacing(0, 32, throttle), (Duration::ZERO, 1));
+    }
+
+    #[tokio::test]
+    async fn zip_stays_readable_after_a_failed_add() {
+        let dir = tempfile::tempdir().unwrap();
+        tokio::fs::write(dir.path().join("a.tsv"), ARCHIVE)
+            .await
+            .unwrap();
+        let path = dir.path().join("out.zip");
+        let mut zip = ZipOutput::create(&path, ZipCompression::Deflate)
+            .await
+            .unwrap();
+        zip.add(&dir.path().join("a.tsv"), Path::new("a.tsv"))
+            .await
+            .unwrap();
+        assert!(zip
+            .add(&dir.path().join("missing.tsv"), Path::new("missing.tsv"))
+            .await
+            .is_err());
+        zip.finish().await.unwrap();
+
+        let file = tokio::io::BufReader::new(File::open(&path).await.unwrap());
+        let reader = async_zip::tokio::read::seek::ZipFileReader::with_tokio(file)
+            .await
+            .unwrap();
+        let names: Vec<_> = reader
+            .file()
+            .entries()
+            .iter()
+            .map(|entry| entry.filename().as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, ["a.tsv"]);
+        let raw = tokio::fs::read(&path).await.unwrap();
+        assert!(!raw.windows(11).any(|window| window == b"missing.tsv"));
+    }
+
+    #[test]
+    fn count_only_rejects_flags_that_save_output() {
+        assert!(parse(&["--count-only"]).is_ok());
+        for flag in [
+            &["-d"][..],
+            &["--group-by-hour", "g.json"],
+            &["--csv"],
+            &["--zip", "a.zip"],
+            &["--manifest", "m.json"],
+        ] {
+            let args: Vec<_> = ["--count-only"].iter().chain(flag).copied().collect();
+            assert!(parse(&args).is_err(), "{:?}", flag);
+        }
+    }
+
+    #[test]
+    fn archive_stem_keeps_dots_in_the_name() {
+        assert_eq!(
+            archive_stem(Path::new("in/web.prod.tsv")),
+            Path::new("in/web.prod")
+        );
+        assert_eq!(
+            archive_stem(Path::new("in/web.stage.tsv.gz")),
+            Path::new("in/web.stage")
+        );
+        assert_eq!(
+            archive_stem(Path::new("2024-01-02-05.ndjson")),
+            Path::new("2024-01-02-05")
+        );
+    }
+
+    #[test]
+    fn only_trailing_404s_are_not_archived_yet() {
+        let not_found = |time: &str| {
+            anyhow::Error::from(CliError::BadResponse(
+                time.to_string(),
+                StatusCode::NOT_FOUND,
+            ))
+        };
+        let newest = "2024-01-02-05".to_string();
+        let (trailing, gaps) = split_not_archived(
+            vec![
+                (0, not_found("2024-01-02-03")),
+                (3, not_found("2024-01-02-06")),
+            ],
+            Some(&newest),
+        );
+        assert_eq!(trailing, ["2024-01-02-06"]);
+        assert_eq!(
+            gaps.iter().map(|(index, _)| *index).collect::<Vec<_>>(),
+            [0]
+        );
+
+        // With nothing archived there is nothing for a 404 to trail.
+        let (trailing, gaps) = split_not_archived(vec![(0, not_found("2024-01-02-06"))], None);
+        assert!(trailing.is_empty());
+        assert_eq!(gaps.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn coverage_only_excuses_trailing_hours_that_arent_archived() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().to_str().unwrap();
+        tokio::fs::write(dir.path().join("2024-01-02-05.tsv"), ARCHIVE)
+            .await
+            .unwrap();
+        let cli = cli(&[
+            "-o",
+            out,
+            "--start",
+            "2024-01-02T04:00:00",
+            "--end",
+            "2024-01-02T06:00:00",
+            "--verify-window-coverage",
+        ]);
+        let hours = |hours: &[&str]| {
+            hours
+                .iter()
+                .map(|hour| hour.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let error = cli
+            .verify_window_coverage(&hours(&["2024-01-02-04", "2024-01-02-06"]))
+            .await
+            .unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("2024-01-02-04"), "{}", message);
+        assert!(!message.contains("2024-01-02-06"), "{}", message);
+        cli.verify_window_coverage(&hours(&["2024-01-02-06"]))
+            .await
+            .unwrap_err();
+
+        tokio::fs::write(dir.path().join("2024-01-02-04.tsv"), ARCHIVE)
+            .await
+            .unwrap();
+        cli.verify_window_coverage(&hours(&["2024-01-02-06"]))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn events_are_tallied_once_and_never_in_dry_runs() {
+        let out = tempfile::tempdir().unwrap();
+        let dir = out.path().to_str().unwrap();
+        let grouped = out.path().join("grouped.json");
+        let archive = ARCHIVE.replace("boom", "boom  again");
+        let run = run(&[
+            "-o",
+            dir,
+            "--csv",
+            "-d",
+            "--group-by-hour",
+            grouped.to_str().unwrap(),
+            "--canonicalize-messages",
+            "--alert-on-severity",
+            "error",
+        ]);
+
+        run.validate_events(tsv_events(archive.as_bytes(), false))
+            .await
+            .unwrap();
+        assert_eq!(run.canonicalized.load(Ordering::Relaxed), 0);
+        assert!(!run.alerted.load(Ordering::Relaxed));
+
+        run.process_archive(
+            Cursor::new(archive.into_bytes()),
+            Some(&out.path().join("a.csv")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(run.canonicalized.load(Ordering::Relaxed), 1);
+        assert!(run.alerted.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn strict_conversion_reports_rows_with_the_wrong_number_of_fields() {
+        let first = ARCHIVE.lines().next().unwrap();
+        for row in ["1004\tshort\trow\tof\tfive", &format!("{}\textra", first)] {
+            let archive = format!("{}\n{}\n", first, row);
+            let mut events = tsv_events(archive.as_bytes(), true);
+            events.next().await.unwrap().unwrap();
+            let error = events.next().await.unwrap().unwrap_err();
+            match error.downcast_ref::<CliError>() {
+                Some(CliError::MalformedRecord {
+                    record, line, raw, ..
+                }) => {
+                    assert_eq!((*record, *line), (2, 2));
+                    assert_eq!(raw, row);
+                }
+                _ => panic!("unexpected error: {:?}", error),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn filters_may_stop_reading_early() {
+        let cli = cli(&["-d", "--filter-command", "head -n 1"]);
+        // Far more than a pipe holds, so the body is still arriving when `head` exits.
+        let archive = gzip(ARCHIVE.repeat(5000).as_bytes()).await;
+        let mut body = tokio_stream::iter(
+            archive
+                .chunks(4096)
+                .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+                .collect::<Vec<_>>(),
+        );
+        let mut out = vec![];
+        let downloaded = cli
+            .decode_body("2024-01-02-05", &mut body, &mut out, true)
+            .await
+            .unwrap();
+        assert_eq!(downloaded, archive.len() as u64);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("{}\n", ARCHIVE.lines().next().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn ndjson_replays_captured_search_results() {
+        let lines = concat!(
+            r#"{"id":"1001","generated_at":"g","received_at":"r","source_id":42,"source_name":"web-1","#,
+            r#""source_ip":"10.0.0.1","facility":"User","severity":"Error","program":null,"message":"boom"}"#,
+            "\n\n",
+            r#"{"id":1002,"generated_at":"g","received_at":"r","source_id":43,"source_name":"web-2","#,
+            r#""source_ip":"10.0.0.2","facility_name":"User","severity_name":"Info","program":"app","message":"ok"}"#,
+            "\n",
+        );
+        let events: Vec<Event> = ndjson_events(lines.as_bytes())
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(
+            events
+                .iter()
+                .map(|event| (
+                    event.id,
+                    event.severity_name.as_str(),
+                    event.program.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            [(1001, "Error", ""), (1002, "Info", "app")]
+        );
+
+        let mut malformed = ndjson_events(&br#"{"id":"x"}"#[..]);
+        assert!(malformed.next().await.unwrap().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_batches_span_files() {
+        // Batches of 10 events, one every 10ms.
+        let limiter = RateLimiter::new(1000);
+        let start = Instant::now();
+        // Three small files' worth of events still fit in the first batch.
+        for _ in 0..6 {
+            limiter.pace().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        for _ in 6..11 {
+            limiter.pace().await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,154 @@
+//! Papertrail's search API.
+//!
+//! A saved search is looked up with `GET /api/v1/searches/<id>.json`, then its query is run with
+//! `GET /api/v1/events/search.json`. Passing `min_time` makes the search page forward through
+//! time: each response carries a `max_id`, which becomes the next request's `min_id`, until a page
+//! comes back empty or the response reports that it reached `max_time`.
+//!
+//! See <https://www.papertrail.com/help/search-api/> and
+//! <https://www.papertrail.com/help/settings-api/#searches>.
+
+use crate::{Event, SourceIp};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::Deserialize;
+use tokio_stream::Stream;
+
+const API_URL: &str = "https://papertrailapp.com/api/v1";
+
+#[derive(Debug, Deserialize)]
+pub struct SavedSearch {
+    pub name: String,
+    pub query: String,
+    pub group: Option<SearchGroup>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchGroup {
+    pub id: u64,
+}
+
+impl SavedSearch {
+    pub async fn fetch(client: &Client, id: u64) -> Result<Self> {
+        client
+            .get(format!("{}/searches/{}.json", API_URL, id))
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("Couldn't fetch saved search {}", id))?
+            .json()
+            .await
+            .with_context(|| format!("Invalid response for saved search {}", id))
+    }
+}
+
+/// One page of search results.
+#[derive(Debug, Deserialize)]
+struct SearchResponse {
+    events: Vec<SearchEvent>,
+    max_id: Option<String>,
+    #[serde(default)]
+    reached_time_limit: bool,
+}
+
+/// An event as the search API returns it. The field names differ from the archive columns.
+#[derive(Debug, Deserialize)]
+pub struct SearchEvent {
+    id: String,
+    generated_at: String,
+    received_at: String,
+    source_id: u32,
+    source_name: String,
+    source_ip: SourceIp,
+    facility: String,
+    severity: String,
+    program: Option<String>,
+    message: String,
+}
+
+impl TryFrom<SearchEvent> for Event {
+    type Error = anyhow::Error;
+
+    fn try_from(event: SearchEvent) -> Result<Self> {
+        Ok(Event {
+            id: event
+                .id
+                .parse()
+                .with_context(|| format!("Invalid event id {:?}", event.id))?,
+            generated_at: event.generated_at,
+            received_at: event.received_at,
+            source_id: event.source_id,
+            source_name: event.source_name,
+            source_ip: event.source_ip,
+            facility_name: event.facility,
+            severity_name: event.severity,
+            program: event.program.unwrap_or_default(),
+            message: event.message,
+        })
+    }
+}
+
+/// A search over a time window.
+pub struct SearchQuery {
+    query: String,
+    group_id: Option<u64>,
+    min_time: i64,
+    max_time: i64,
+}
+
+impl SearchQuery {
+    pub fn new(search: &SavedSearch, start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        Self {
+            query: search.query.clone(),
+            group_id: search.group.as_ref().map(|group| group.id),
+            min_time: start.timestamp(),
+            max_time: end.timestamp(),
+        }
+    }
+
+    async fn page(&self, client: &Client, min_id: Option<&str>) -> Result<SearchResponse> {
+        let mut request = client
+            .get(format!("{}/events/search.json", API_URL))
+            .query(&[("q", self.query.as_str())])
+            .query(&[("min_time", self.min_time), ("max_time", self.max_time)]);
+        if let Some(group_id) = self.group_id {
+            request = request.query(&[("group_id", group_id)]);
+        }
+        if let Some(min_id) = min_id {
+            request = request.query(&[("min_id", min_id)]);
+        }
+        request
+            .send()
+            .await?
+            .error_for_status()
+            .context("Search request failed")?
+            .json()
+            .await
+            .context("Invalid search response")
+    }
+
+    /// Every matching event, oldest first, fetching pages as needed.
+    pub fn events<'a>(&'a self, client: &'a Client) -> impl Stream<Item = Result<Event>> + 'a {
+        let pages = futures::stream::try_unfold(Some(None::<String>), move |cursor| async move {
+            let Some(min_id) = cursor else {
+                return Ok(None);
+            };
+            let page = self.page(client, min_id.as_deref()).await?;
+            let next = match page.max_id.clone() {
+                Some(max_id)
+                    if !page.events.is_empty()
+                        && !page.reached_time_limit
+                        && min_id.as_ref() != Some(&max_id) =>
+                {
+                    Some(Some(max_id))
+                }
+                _ => None,
+            };
+            Ok::<_, anyhow::Error>(Some((page.events, next)))
+        });
+        futures::TryStreamExt::try_flatten(futures::TryStreamExt::map_ok(pages, |events| {
+            futures::stream::iter(events.into_iter().map(Event::try_from))
+        }))
+    }
+}